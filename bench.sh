@@ -0,0 +1,42 @@
+#!/bin/bash
+
+# bench/*.c を -O なしと -O でコンパイルして実行時間を比べる
+# 実行時間は3回のうち最も速いものを秒で表示する
+rust9cc="./target/debug/rust9cc"
+runs=3
+
+# ./tmp を runs 回実行して最短の時間を表示する
+measure() {
+    best=""
+    for _ in $(seq "$runs"); do
+        start=$(date +%s%N)
+        ./tmp
+        end=$(date +%s%N)
+        elapsed=$((end - start))
+        if [ -z "$best" ] || [ "$elapsed" -lt "$best" ]; then
+            best="$elapsed"
+        fi
+    done
+    printf "%d.%03d" $((best / 1000000000)) $((best / 1000000 % 1000))
+}
+
+printf "%-12s %8s %8s %8s\n" program -O0 -O speedup
+for input in bench/*.c; do
+    ${rust9cc} "$input" > tmp.s && cc -z noexecstack -o tmp tmp.s || exit 1
+    ./tmp
+    expected="$?"
+    o0=$(measure)
+
+    ${rust9cc} -O "$input" > tmp.s && cc -z noexecstack -o tmp tmp.s || exit 1
+    ./tmp
+    actual="$?"
+    if [ "$actual" != "$expected" ]; then
+        echo "$input: -O returned $actual, but $expected without it"
+        exit 1
+    fi
+    o1=$(measure)
+
+    speedup=$(awk "BEGIN { printf \"%.2fx\", $o0 / $o1 }")
+    printf "%-12s %8s %8s %8s\n" "$(basename "$input")" "$o0" "$o1" "$speedup"
+done
+rm -f tmp tmp.s
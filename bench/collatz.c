@@ -0,0 +1,13 @@
+// 1から n までのコラッツ数列の長さの合計
+total = 0;
+for (n = 1; n < 600000; n = n + 1) {
+    x = n;
+    while (x != 1) {
+        if (x / 2 * 2 == x)
+            x = x / 2;
+        else
+            x = 3 * x + 1;
+        total = total + 1;
+    }
+}
+return total - total / 256 * 256;
@@ -0,0 +1,10 @@
+// フィボナッチ数列を 10007 で割った余りを繰り返し計算する
+a = 0;
+b = 1;
+for (i = 0; i < 60000000; i = i + 1) {
+    c = a + b;
+    c = c - c / 10007 * 10007;
+    a = b;
+    b = c;
+}
+return a - a / 256 * 256;
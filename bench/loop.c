@@ -0,0 +1,7 @@
+// 三重ループで足し算を繰り返す
+s = 0;
+for (i = 0; i < 500; i = i + 1)
+    for (j = 0; j < 500; j = j + 1)
+        for (k = 0; k < 500; k = k + 1)
+            s = s + i * j - k;
+return s - s / 256 * 256;
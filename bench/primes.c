@@ -0,0 +1,10 @@
+// 試し割りで素数を数える
+count = 0;
+for (n = 2; n < 200000; n = n + 1) {
+    prime = 1;
+    for (d = 2; d * d <= n; d = d + 1)
+        if (n / d * d == n)
+            prime = 0;
+    count = count + prime;
+}
+return count - count / 256 * 256;
@@ -6,6 +6,42 @@ pub struct LocalVar {
     pub ident: Box<String>,
 }
 
+// 拡張asmのオペランド "constraint"(expr)
+#[derive(Debug, PartialEq)]
+pub struct AsmOperand {
+    pub constraint: String,
+    pub expr: Box<Node>,
+}
+
+// 拡張asmのテンプレート中の %0, %1, ... をオペランドで置き換える
+// %% は % になる。オペランドが無い番号があればその番号を返す
+pub fn substitute_asm_operands(template: &str, operands: &[String]) -> Result<String, usize> {
+    let mut out = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        match chars.peek() {
+            Some('%') => {
+                chars.next();
+                out.push('%');
+            }
+            Some(d) if d.is_ascii_digit() => {
+                let mut index = 0usize;
+                while let Some(n) = chars.peek().and_then(|d| d.to_digit(10)) {
+                    index = index.saturating_mul(10).saturating_add(n as usize);
+                    chars.next();
+                }
+                out.push_str(operands.get(index).ok_or(index)?);
+            }
+            _ => out.push('%'),
+        }
+    }
+    Ok(out)
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Node {
     Num(u32),
@@ -38,6 +74,32 @@ pub enum Node {
     Block {
         stmts: Vec<Box<Node>>,
     },
+    Asm {
+        template: String,
+        extended: bool, // ":" があれば拡張asmで、テンプレートの %0, %1, ... を置き換える
+        outputs: Vec<AsmOperand>,
+        inputs: Vec<AsmOperand>,
+        clobbers: Vec<String>,
+    },
+}
+
+impl Node {
+    // 整数定数式として評価する
+    // 変数の参照や代入、0除算を含む場合はNone
+    pub fn eval_const(&self) -> Option<i64> {
+        match self {
+            Node::Num(n) => Some(*n as i64),
+            Node::Add { l, r } => Some(l.eval_const()?.wrapping_add(r.eval_const()?)),
+            Node::Sub { l, r } => Some(l.eval_const()?.wrapping_sub(r.eval_const()?)),
+            Node::Mul { l, r } => Some(l.eval_const()?.wrapping_mul(r.eval_const()?)),
+            Node::Div { l, r } => l.eval_const()?.checked_div(r.eval_const()?),
+            Node::Lt { l, r } => Some((l.eval_const()? < r.eval_const()?) as i64),
+            Node::Le { l, r } => Some((l.eval_const()? <= r.eval_const()?) as i64),
+            Node::Eq { l, r } => Some((l.eval_const()? == r.eval_const()?) as i64),
+            Node::Ne { l, r } => Some((l.eval_const()? != r.eval_const()?) as i64),
+            _ => None,
+        }
+    }
 }
 
 impl fmt::Display for Node {
@@ -78,6 +140,24 @@ impl fmt::Display for Node {
                 }
                 write!(f, "}}")
             },
+            Node::Asm { template, extended, outputs, inputs, clobbers } => {
+                write!(f, "(asm {:?}", template)?;
+                if *extended {
+                    for operands in [outputs, inputs] {
+                        write!(f, " :")?;
+                        for (i, op) in operands.iter().enumerate() {
+                            let sep = if i == 0 { " " } else { ", " };
+                            write!(f, "{}{:?}({})", sep, op.constraint, op.expr)?;
+                        }
+                    }
+                    write!(f, " :")?;
+                    for (i, clobber) in clobbers.iter().enumerate() {
+                        let sep = if i == 0 { " " } else { ", " };
+                        write!(f, "{}{:?}", sep, clobber)?;
+                    }
+                }
+                write!(f, ")")
+            },
         }
     }
 }
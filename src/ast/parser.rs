@@ -2,7 +2,7 @@ use std::iter::Peekable;
 
 use thiserror::Error;
 
-use crate::ast::node::{LocalVar, Node, Nodes};
+use crate::ast::node::{substitute_asm_operands, AsmOperand, LocalVar, Node, Nodes};
 use crate::lexer::Token;
 
 const LOCAL_VAR_OFFSET: usize = 8;
@@ -18,6 +18,14 @@ pub enum ParserError {
     UnexpectedEOF,
     #[error("not enough tokens")]
     NotEnoughTokens,
+    #[error("expression is not an integer constant expression")]
+    NotConstantExpression,
+    #[error("static assertion failed: {0}")]
+    StaticAssertionFailed(String),
+    #[error("invalid asm operand: {0:?}")]
+    InvalidAsmOperand(String),
+    #[error("asm operand number out of range: %{0}")]
+    InvalidAsmOperandNumber(usize),
 }
 
 type Result<T> = std::result::Result<T, ParserError>;
@@ -81,6 +89,60 @@ impl<'a, T: Iterator<Item = &'a Token>> Parser<'a, T> {
         self.locals.last().unwrap().clone()
     }
 
+    // str = string+
+    // 連続する文字列リテラルは連結する
+    fn string_literal(&mut self) -> Result<String> {
+        let mut s = String::new();
+        let mut read = false;
+        loop {
+            match self.peek()?.clone() {
+                Token::Str(t) => {
+                    self.consume(Token::Str(t.clone()))?;
+                    s.push_str(&t);
+                    read = true;
+                }
+                other if !read => {
+                    return Err(ParserError::UnexpectedToken {
+                        expected: vec![Token::Str(Box::default())],
+                        actual: vec![other],
+                    })
+                }
+                _ => return Ok(s),
+            }
+        }
+    }
+
+    // asm_operands = (str "(" expr ")" ("," str "(" expr ")")*)?
+    fn asm_operands(&mut self) -> Result<Vec<AsmOperand>> {
+        let mut operands = Vec::new();
+        if !matches!(self.peek()?, Token::Str(_)) {
+            return Ok(operands);
+        }
+        loop {
+            let constraint = self.string_literal()?;
+            self.consume(Token::LeftParen)?;
+            let expr = self.expr()?;
+            self.consume(Token::RightParen)?;
+
+            // 出力とメモリオペランドは変数、即値オペランドは定数式でなければならない
+            let valid = if constraint.contains('=') || constraint.contains('+') || constraint.contains('m') {
+                matches!(*expr, Node::Lvar(_))
+            } else if constraint.contains('i') {
+                expr.eval_const().is_some()
+            } else {
+                constraint.contains('r')
+            };
+            if !valid {
+                return Err(ParserError::InvalidAsmOperand(constraint));
+            }
+
+            operands.push(AsmOperand { constraint, expr });
+            if self.consume(Token::Comma).is_err() {
+                return Ok(operands);
+            }
+        }
+    }
+
     // program = stmt*
     fn program(&mut self) -> Result<Nodes> {
         let mut nodes = Vec::new();
@@ -95,6 +157,8 @@ impl<'a, T: Iterator<Item = &'a Token>> Parser<'a, T> {
     //      | "if" "(" expr ")" stmt ("else" stmt)?
     //      | "while" "(" expr ")" stmt
     //      | "for" "(" expr? ";" expr? ";" expr? ")" stmt
+    //      | "_Static_assert" "(" expr ("," str)? ")" ";"
+    //      | "asm" "volatile"? "(" str (":" asm_operands (":" asm_operands (":" (str ("," str)*)?)?)?)? ")" ";"
     //      | expr ";"
     fn stmt(&mut self) -> Result<Box<Node>> {
         let next_token = self.peek()?.clone();
@@ -139,7 +203,7 @@ impl<'a, T: Iterator<Item = &'a Token>> Parser<'a, T> {
                 self.consume(Token::LeftParen)?;
 
                 // expr? ";"
-                let init = if self.consume(Token::Semicolon).is_ok() {
+                let init = if *self.peek()? == Token::Semicolon {
                     None
                 } else {
                     Some(self.expr()?)
@@ -147,7 +211,7 @@ impl<'a, T: Iterator<Item = &'a Token>> Parser<'a, T> {
                 self.consume(Token::Semicolon)?;
 
                 // expr? ";"
-                let cond = if self.consume(Token::Semicolon).is_ok() {
+                let cond = if *self.peek()? == Token::Semicolon {
                     None
                 } else {
                     Some(self.expr()?)
@@ -155,7 +219,7 @@ impl<'a, T: Iterator<Item = &'a Token>> Parser<'a, T> {
                 self.consume(Token::Semicolon)?;
 
                 // expr? ")"
-                let step = if self.consume(Token::RightParen).is_ok() {
+                let step = if *self.peek()? == Token::RightParen {
                     None
                 } else {
                     Some(self.expr()?)
@@ -170,6 +234,75 @@ impl<'a, T: Iterator<Item = &'a Token>> Parser<'a, T> {
                     then,
                 }))
             }
+            Token::StaticAssert => {
+                self.consume(Token::StaticAssert)?;
+                self.consume(Token::LeftParen)?;
+                let cond = self.expr()?;
+                let message = if self.consume(Token::Comma).is_ok() {
+                    self.string_literal()?
+                } else {
+                    String::new()
+                };
+                self.consume(Token::RightParen)?;
+                self.consume(Token::Semicolon)?;
+
+                // 条件はパース時に評価し、コードは生成しない
+                match cond.eval_const() {
+                    Some(0) => Err(ParserError::StaticAssertionFailed(message)),
+                    Some(_) => Ok(Box::new(Node::Block { stmts: Vec::new() })),
+                    None => Err(ParserError::NotConstantExpression),
+                }
+            }
+            Token::Asm => {
+                self.consume(Token::Asm)?;
+                // volatileはキーワードではないので識別子として読み飛ばす
+                if let Token::Identifier(s) = self.peek()?.clone() {
+                    if *s == "volatile" || *s == "__volatile__" {
+                        self.consume(Token::Identifier(s))?;
+                    }
+                }
+                self.consume(Token::LeftParen)?;
+                let template = self.string_literal()?;
+
+                let mut outputs = Vec::new();
+                let mut inputs = Vec::new();
+                let mut clobbers = Vec::new();
+                let extended = self.consume(Token::Colon).is_ok();
+                if extended {
+                    outputs = self.asm_operands()?;
+                    if self.consume(Token::Colon).is_ok() {
+                        inputs = self.asm_operands()?;
+                        if self.consume(Token::Colon).is_ok() && matches!(self.peek()?, Token::Str(_)) {
+                            clobbers.push(self.string_literal()?);
+                            while self.consume(Token::Comma).is_ok() {
+                                clobbers.push(self.string_literal()?);
+                            }
+                        }
+                    }
+                }
+                if let Some(op) = inputs.iter().find(|op| op.constraint.contains('=') || op.constraint.contains('+')) {
+                    return Err(ParserError::InvalidAsmOperand(op.constraint.clone()));
+                }
+                if let Some(op) = outputs.iter().find(|op| !op.constraint.contains('=') && !op.constraint.contains('+')) {
+                    return Err(ParserError::InvalidAsmOperand(op.constraint.clone()));
+                }
+
+                // テンプレートの %N はオペランドの数より小さくなければならない
+                if extended {
+                    let operands = vec![String::new(); outputs.len() + inputs.len()];
+                    substitute_asm_operands(&template, &operands).map_err(ParserError::InvalidAsmOperandNumber)?;
+                }
+
+                self.consume(Token::RightParen)?;
+                self.consume(Token::Semicolon)?;
+                Ok(Box::new(Node::Asm {
+                    template,
+                    extended,
+                    outputs,
+                    inputs,
+                    clobbers,
+                }))
+            }
             _ => {
                 let node = self.expr()?;
                 self.consume(Token::Semicolon)?;
@@ -510,6 +643,13 @@ mod tests {
                 expected: Some("(x[rbp-8] = 1); (for ((i[rbp-16] = 0); (i[rbp-16] < 10); (i[rbp-16] = (i[rbp-16] + 1))) { (x[rbp-8] = (x[rbp-8] + 1)); (1 + 2); }); "),
                 expected_error: None,
             },
+            Test {
+                success: true,
+                name: "for with empty clauses",
+                input: "for (;;) x = 1; for (i=0;;) 1;",
+                expected: Some("(for (; ; ) (x[rbp-8] = 1)); (for ((i[rbp-16] = 0); ; ) 1); "),
+                expected_error: None,
+            },
             Test {
                 success: true,
                 name: "block",
@@ -517,6 +657,71 @@ mod tests {
                 expected: Some("{ (x[rbp-8] = 1); (y[rbp-16] = 2); (z[rbp-24] = 3); }; "),
                 expected_error: None,
             },
+            Test {
+                success: true,
+                name: "static assert",
+                input: "_Static_assert((1 + 2) * 3 == 9, \"ok\"); _Static_assert(4 / 2 < 3);",
+                expected: Some("{ }; { }; "),
+                expected_error: None,
+            },
+            Test {
+                success: true,
+                name: "basic asm",
+                input: "asm(\"nop\\n\" \"nop\");",
+                expected: Some("(asm \"nop\\nnop\"); "),
+                expected_error: None,
+            },
+            Test {
+                success: true,
+                name: "extended asm",
+                input: "y = 1; __asm__ volatile (\"lea %0, [%1+%2]\" : \"=r\"(x) : \"r\"(y * 2), \"i\"(3) : \"rax\", \"memory\");",
+                expected: Some("(y[rbp-8] = 1); (asm \"lea %0, [%1+%2]\" : \"=r\"(x[rbp-16]) : \"r\"((y[rbp-8] * 2)), \"i\"(3) : \"rax\", \"memory\"); "),
+                expected_error: None,
+            },
+            Test {
+                success: true,
+                name: "basic asm is not substituted",
+                input: "asm(\"# 100%1 %%\");",
+                expected: Some("(asm \"# 100%1 %%\"); "),
+                expected_error: None,
+            },
+            Test {
+                success: false,
+                name: "asm operand number out of range",
+                input: "asm(\"mov %0, %2\" : \"=r\"(x) : \"r\"(1));",
+                expected: None,
+                expected_error: Some(ParserError::InvalidAsmOperandNumber(2)),
+            },
+            Test {
+                success: false,
+                name: "asm output is not a variable",
+                input: "asm(\"\" : \"=r\"(1));",
+                expected: None,
+                expected_error: Some(ParserError::InvalidAsmOperand("=r".to_string())),
+            },
+            Test {
+                success: false,
+                name: "asm immediate is not a constant",
+                input: "asm(\"\" : : \"i\"(x));",
+                expected: None,
+                expected_error: Some(ParserError::InvalidAsmOperand("i".to_string())),
+            },
+            Test {
+                success: false,
+                name: "static assertion failed",
+                input: "_Static_assert(1 > 2, \"1 must be greater than 2\");",
+                expected: None,
+                expected_error: Some(ParserError::StaticAssertionFailed(
+                    "1 must be greater than 2".to_string(),
+                )),
+            },
+            Test {
+                success: false,
+                name: "static assert with variable",
+                input: "x = 1; _Static_assert(x == 1, \"x\");",
+                expected: None,
+                expected_error: Some(ParserError::NotConstantExpression),
+            },
             Test {
                 success: false,
                 name: "unexpected token 1",
@@ -541,7 +746,7 @@ mod tests {
 
         for t in tests {
             let mut c = t.input.chars().peekable();
-            let tokens = tokenize(&mut c);
+            let tokens = tokenize(&mut c).unwrap();
             let mut token_iter = tokens.iter();
             let mut parser = Parser::new(&mut token_iter);
             match parser.program() {
@@ -0,0 +1,504 @@
+use thiserror::Error;
+
+use crate::ast::node::{LocalVar, Node, Nodes};
+
+const MAGIC: &[u8; 4] = b"R9BC";
+const VERSION: u8 = 1;
+
+// ネイティブのバックエンドと同じく、変数は26個まで
+pub const MAX_SLOTS: usize = 26;
+const WORD_SIZE: usize = 8;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum BytecodeError {
+    #[error("too many local variables: {0}")]
+    TooManyVariables(String),
+    #[error("inline asm cannot be compiled to bytecode")]
+    InlineAsm,
+    #[error("statement used as an expression: {0}")]
+    NotExpression(String),
+    #[error("not a bytecode file")]
+    InvalidMagic,
+    #[error("unsupported bytecode version: {0}")]
+    UnsupportedVersion(u8),
+    #[error("unknown opcode: {0:#04x}")]
+    UnknownOpcode(u8),
+    #[error("unexpected end of bytecode")]
+    Truncated,
+}
+
+type Result<T> = std::result::Result<T, BytecodeError>;
+
+// スタックマシンの命令
+// ジャンプ先は命令列の中の位置
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Op {
+    Push(i64),
+    Load(u8),  // スロットの値を積む
+    Store(u8), // スタックトップをスロットに書く (値は残す)
+    Pop,       // 式文の値を捨てる (returnが無いときの戻り値として覚えておく)
+    Drop,      // 式文ではない式の値を捨てる
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Jmp(u32),
+    Jz(u32), // 取り出した値が0ならジャンプ
+    Ret,     // 取り出した値を返して終了
+    Halt,    // 最後にPopした値を返して終了
+}
+
+impl Op {
+    fn opcode(self) -> u8 {
+        match self {
+            Op::Push(_) => 0x01,
+            Op::Load(_) => 0x02,
+            Op::Store(_) => 0x03,
+            Op::Pop => 0x04,
+            Op::Drop => 0x05,
+            Op::Add => 0x10,
+            Op::Sub => 0x11,
+            Op::Mul => 0x12,
+            Op::Div => 0x13,
+            Op::Eq => 0x20,
+            Op::Ne => 0x21,
+            Op::Lt => 0x22,
+            Op::Le => 0x23,
+            Op::Jmp(_) => 0x30,
+            Op::Jz(_) => 0x31,
+            Op::Ret => 0x40,
+            Op::Halt => 0x41,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Program {
+    pub slots: u8, // 使う変数のスロット数
+    pub code: Vec<Op>,
+}
+
+impl Program {
+    // ファイルに書き出す形式
+    // "R9BC", バージョン, スロット数, 命令数(u32), 命令列
+    // 即値は符号付きLEB128、スロットは1バイト、ジャンプ先はu32
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+        out.push(self.slots);
+        out.extend_from_slice(&(self.code.len() as u32).to_le_bytes());
+        for op in &self.code {
+            out.push(op.opcode());
+            match op {
+                Op::Push(n) => sleb128(&mut out, *n),
+                Op::Load(slot) | Op::Store(slot) => out.push(*slot),
+                Op::Jmp(target) | Op::Jz(target) => out.extend_from_slice(&target.to_le_bytes()),
+                _ => {}
+            }
+        }
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        if !is_bytecode(bytes) {
+            return Err(BytecodeError::InvalidMagic);
+        }
+        let mut reader = Reader {
+            bytes,
+            pos: MAGIC.len(),
+        };
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(BytecodeError::UnsupportedVersion(version));
+        }
+        let slots = reader.u8()?;
+        let len = reader.u32()?;
+
+        let mut code = Vec::new();
+        for _ in 0..len {
+            let op = match reader.u8()? {
+                0x01 => Op::Push(reader.sleb128()?),
+                0x02 => Op::Load(reader.u8()?),
+                0x03 => Op::Store(reader.u8()?),
+                0x04 => Op::Pop,
+                0x05 => Op::Drop,
+                0x10 => Op::Add,
+                0x11 => Op::Sub,
+                0x12 => Op::Mul,
+                0x13 => Op::Div,
+                0x20 => Op::Eq,
+                0x21 => Op::Ne,
+                0x22 => Op::Lt,
+                0x23 => Op::Le,
+                0x30 => Op::Jmp(reader.u32()?),
+                0x31 => Op::Jz(reader.u32()?),
+                0x40 => Op::Ret,
+                0x41 => Op::Halt,
+                opcode => return Err(BytecodeError::UnknownOpcode(opcode)),
+            };
+            code.push(op);
+        }
+        Ok(Program { slots, code })
+    }
+}
+
+// バイトコードのファイルかどうか
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn u8(&mut self) -> Result<u8> {
+        let byte = *self.bytes.get(self.pos).ok_or(BytecodeError::Truncated)?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos + 4)
+            .ok_or(BytecodeError::Truncated)?;
+        self.pos += 4;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn sleb128(&mut self) -> Result<i64> {
+        let mut result = 0i64;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                result |= ((byte & 0x7f) as i64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    result |= -1 << shift;
+                }
+                return Ok(result);
+            }
+        }
+    }
+}
+
+fn sleb128(out: &mut Vec<u8>, mut n: i64) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        // 残りが符号ビットだけになったら終わり
+        if (n == 0 && byte & 0x40 == 0) || (n == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+// ASTをバイトコードにコンパイルする
+pub fn compile(nodes: &Nodes) -> Result<Program> {
+    let mut compiler = Compiler::default();
+    for node in &nodes.0 {
+        compiler.stmt(node)?;
+    }
+    compiler.code.push(Op::Halt);
+    Ok(Program {
+        slots: compiler.slots,
+        code: compiler.code,
+    })
+}
+
+#[derive(Default)]
+struct Compiler {
+    code: Vec<Op>,
+    slots: u8,
+}
+
+impl Compiler {
+    // 今の位置 (次に出力する命令の番号)
+    fn pos(&self) -> u32 {
+        self.code.len() as u32
+    }
+
+    // 後でジャンプ先を埋める命令を出力する
+    fn jump(&mut self, op: fn(u32) -> Op) -> usize {
+        self.code.push(op(0));
+        self.code.len() - 1
+    }
+
+    fn patch(&mut self, index: usize) {
+        let target = self.pos();
+        self.code[index] = match self.code[index] {
+            Op::Jmp(_) => Op::Jmp(target),
+            Op::Jz(_) => Op::Jz(target),
+            op => unreachable!("not a jump: {:?}", op),
+        };
+    }
+
+    fn slot(&mut self, node: &Node) -> Result<u8> {
+        if let Node::Lvar(LocalVar { ident, offset }) = node {
+            let slot = offset / WORD_SIZE - 1;
+            if slot >= MAX_SLOTS {
+                return Err(BytecodeError::TooManyVariables(ident.to_string()));
+            }
+            self.slots = self.slots.max(slot as u8 + 1);
+            Ok(slot as u8)
+        } else {
+            panic!("代入の左辺値が変数ではありません");
+        }
+    }
+
+    fn stmt(&mut self, node: &Node) -> Result<()> {
+        match node {
+            Node::Return { expr } => {
+                self.expr(expr)?;
+                self.code.push(Op::Ret);
+            }
+            Node::If { cond, then, els } => {
+                self.expr(cond)?;
+                let jz = self.jump(Op::Jz);
+                self.stmt(then)?;
+                if let Some(els) = els {
+                    let jmp = self.jump(Op::Jmp);
+                    self.patch(jz);
+                    self.stmt(els)?;
+                    self.patch(jmp);
+                } else {
+                    self.patch(jz);
+                }
+            }
+            Node::While { cond, then } => {
+                let begin = self.pos();
+                self.expr(cond)?;
+                let jz = self.jump(Op::Jz);
+                self.stmt(then)?;
+                self.code.push(Op::Jmp(begin));
+                self.patch(jz);
+            }
+            Node::For {
+                init,
+                cond,
+                step,
+                then,
+            } => {
+                // 初期化式と更新式は式文ではないので、戻り値を変えずに捨てる
+                if let Some(init) = init {
+                    self.expr(init)?;
+                    self.code.push(Op::Drop);
+                }
+                let begin = self.pos();
+                // 条件式が無ければ無限ループ
+                let jz = match cond {
+                    Some(cond) => {
+                        self.expr(cond)?;
+                        Some(self.jump(Op::Jz))
+                    }
+                    None => None,
+                };
+                self.stmt(then)?;
+                if let Some(step) = step {
+                    self.expr(step)?;
+                    self.code.push(Op::Drop);
+                }
+                self.code.push(Op::Jmp(begin));
+                if let Some(jz) = jz {
+                    self.patch(jz);
+                }
+            }
+            Node::Block { stmts } => {
+                for stmt in stmts {
+                    self.stmt(stmt)?;
+                }
+            }
+            Node::Asm { .. } => return Err(BytecodeError::InlineAsm),
+            expr => {
+                self.expr(expr)?;
+                self.code.push(Op::Pop);
+            }
+        }
+        Ok(())
+    }
+
+    fn expr(&mut self, node: &Node) -> Result<()> {
+        let (op, l, r) = match node {
+            Node::Num(n) => {
+                self.code.push(Op::Push(*n as i64));
+                return Ok(());
+            }
+            Node::Lvar(_) => {
+                let slot = self.slot(node)?;
+                self.code.push(Op::Load(slot));
+                return Ok(());
+            }
+            Node::Assign { l, r } => {
+                let slot = self.slot(l)?;
+                self.expr(r)?;
+                self.code.push(Op::Store(slot));
+                return Ok(());
+            }
+            Node::Add { l, r } => (Op::Add, l, r),
+            Node::Sub { l, r } => (Op::Sub, l, r),
+            Node::Mul { l, r } => (Op::Mul, l, r),
+            Node::Div { l, r } => (Op::Div, l, r),
+            Node::Eq { l, r } => (Op::Eq, l, r),
+            Node::Ne { l, r } => (Op::Ne, l, r),
+            Node::Lt { l, r } => (Op::Lt, l, r),
+            Node::Le { l, r } => (Op::Le, l, r),
+            stmt => return Err(BytecodeError::NotExpression(stmt.to_string())),
+        };
+        self.expr(l)?;
+        self.expr(r)?;
+        self.code.push(op);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::parser::Parser;
+    use crate::lexer::tokenize;
+
+    use super::*;
+
+    fn parse(input: &str) -> Nodes {
+        let tokens = tokenize(&mut input.chars().peekable()).unwrap();
+        Parser::new(tokens.iter()).parse().unwrap()
+    }
+
+    #[test]
+    fn test_compile() {
+        struct Test {
+            name: &'static str,
+            input: &'static str,
+            expected: Vec<Op>,
+        }
+
+        let tests = vec![
+            Test {
+                name: "expression statement",
+                input: "a = 1 + 2;",
+                expected: vec![Op::Push(1), Op::Push(2), Op::Add, Op::Store(0), Op::Pop, Op::Halt],
+            },
+            Test {
+                name: "if else",
+                input: "if (1) return 2; else b = 3;",
+                expected: vec![
+                    Op::Push(1),
+                    Op::Jz(5),
+                    Op::Push(2),
+                    Op::Ret,
+                    Op::Jmp(8),
+                    Op::Push(3),
+                    Op::Store(0),
+                    Op::Pop,
+                    Op::Halt,
+                ],
+            },
+            Test {
+                name: "while",
+                input: "while (i < 3) i = i + 1;",
+                expected: vec![
+                    Op::Load(0),
+                    Op::Push(3),
+                    Op::Lt,
+                    Op::Jz(10),
+                    Op::Load(0),
+                    Op::Push(1),
+                    Op::Add,
+                    Op::Store(0),
+                    Op::Pop,
+                    Op::Jmp(0),
+                    Op::Halt,
+                ],
+            },
+            Test {
+                name: "for",
+                input: "for (i = 0; i < 2; i = i + 1) 5;",
+                expected: vec![
+                    Op::Push(0),
+                    Op::Store(0),
+                    Op::Drop,
+                    Op::Load(0),
+                    Op::Push(2),
+                    Op::Lt,
+                    Op::Jz(15),
+                    Op::Push(5),
+                    Op::Pop,
+                    Op::Load(0),
+                    Op::Push(1),
+                    Op::Add,
+                    Op::Store(0),
+                    Op::Drop,
+                    Op::Jmp(3),
+                    Op::Halt,
+                ],
+            },
+            Test {
+                name: "for without condition",
+                input: "for (;;) return 1;",
+                expected: vec![Op::Push(1), Op::Ret, Op::Jmp(0), Op::Halt],
+            },
+        ];
+
+        for t in tests {
+            let program = compile(&parse(t.input)).unwrap();
+            assert_eq!(program.code, t.expected, "Failed in the {}", t.name);
+        }
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let program = compile(&parse(
+            "a = 3000000000; b = 0 - 64; while (a > b) a = a / 2 - 1; return a == b;",
+        ))
+        .unwrap();
+        let bytes = program.encode();
+        assert!(bytes.starts_with(b"R9BC\x01\x02"));
+        assert_eq!(Program::decode(&bytes), Ok(program));
+    }
+
+    #[test]
+    fn test_decode_error() {
+        struct Test {
+            name: &'static str,
+            input: Vec<u8>,
+            expected: BytecodeError,
+        }
+
+        let tests = vec![
+            Test {
+                name: "magic",
+                input: b"\x7fELF".to_vec(),
+                expected: BytecodeError::InvalidMagic,
+            },
+            Test {
+                name: "version",
+                input: b"R9BC\x02\x00\x00\x00\x00\x00".to_vec(),
+                expected: BytecodeError::UnsupportedVersion(2),
+            },
+            Test {
+                name: "unknown opcode",
+                input: b"R9BC\x01\x00\x01\x00\x00\x00\xff".to_vec(),
+                expected: BytecodeError::UnknownOpcode(0xff),
+            },
+            Test {
+                name: "truncated",
+                input: b"R9BC\x01\x00\x01\x00\x00\x00\x30\x01".to_vec(),
+                expected: BytecodeError::Truncated,
+            },
+        ];
+
+        for t in tests {
+            assert_eq!(Program::decode(&t.input), Err(t.expected), "Failed in the {}", t.name);
+        }
+    }
+}
@@ -1,7 +1,18 @@
-use core::panic;
-use std::{io::{self, Write}};
+use std::io::{self, Write};
 
-use crate::ast::node::{LocalVar, Node, Nodes};
+use crate::ast::node::{substitute_asm_operands, AsmOperand, LocalVar, Node, Nodes};
+use crate::gen::x86_64::{Att, Selector, Syntax};
+use crate::ir;
+
+pub mod aarch64;
+pub mod c;
+pub mod elf;
+pub mod encode;
+pub mod llvm;
+pub mod regalloc;
+pub mod riscv64;
+pub mod wasm;
+pub mod x86_64;
 
 pub trait CodeGen<W: Write> {
     fn prefix(&mut self) -> io::Result<()>;
@@ -12,244 +23,166 @@ pub trait CodeGen<W: Write> {
 
 pub struct AsmCodeGen<W: Write> {
     w: W,
-    label_index_counter: usize,
+    selector: Selector,
+    syntax: Syntax,
 }
 
 impl<W: Write> CodeGen<W> for AsmCodeGen<W> {
     fn prefix(&mut self) -> io::Result<()> {
-        writeln!(self.w, ".intel_syntax noprefix")?;
+        match self.syntax {
+            Syntax::Intel => writeln!(self.w, ".intel_syntax noprefix")?,
+            Syntax::Att => writeln!(self.w, ".att_syntax prefix")?,
+        }
         writeln!(self.w, ".globl main")?;
         writeln!(self.w, "main:")?;
         Ok(())
     }
 
+    // フレームの大きさはIRを作るまで決まらないので、関数全体を gen_from_nodes で出力する
     fn prologue(&mut self) -> io::Result<()> {
-        writeln!(self.w, "  push rbp")?;
-        writeln!(self.w, "  mov rbp, rsp")?;
-        writeln!(self.w, "  sub rsp, 208")?; // FIXME: 208 / 8 = 26個しか変数宣言できない
         Ok(())
     }
 
     fn gen_from_nodes(&mut self, nodes: Nodes) -> io::Result<()> {
-        for node in nodes.0 {
-            self.from_node(*node)?;
-            writeln!(self.w, "  pop rax")?;
-        }
-        Ok(())
+        self.selector.gen_function(&ir::lower(&nodes))?;
+        self.flush()
     }
 
     fn epilogue(&mut self) -> io::Result<()> {
-        writeln!(self.w, "  mov rsp, rbp")?;
-        writeln!(self.w, "  pop rbp")?;
-        writeln!(self.w, "  ret")?;
         Ok(())
     }
 }
 
 impl<W: Write> AsmCodeGen<W> {
     pub fn new(w: W) -> Self {
+        Self::with_syntax(w, Syntax::Intel)
+    }
+
+    pub fn with_syntax(w: W, syntax: Syntax) -> Self {
+        Self::with_options(w, syntax, 0)
+    }
+
+    pub fn with_options(w: W, syntax: Syntax, opt_level: u8) -> Self {
         Self {
             w,
-            label_index_counter: 0,
+            selector: Selector::with_opt_level(opt_level),
+            syntax,
         }
     }
 
-    fn lval(&mut self, node: Node) -> io::Result<()> {
-        if let Node::Lvar(LocalVar { ident: _, offset }) = node {
-            writeln!(self.w, "  mov rax, rbp")?;
-            writeln!(self.w, "  sub rax, {}", offset)?;
-            writeln!(self.w, "  push rax")?;
-            Ok(())
-        } else {
-            panic!("代入の左辺値が変数ではありません");
+    // 選択済みの命令をテキストで書き出す
+    fn flush(&mut self) -> io::Result<()> {
+        for inst in self.selector.take() {
+            match self.syntax {
+                Syntax::Intel => writeln!(self.w, "{}", inst)?,
+                Syntax::Att => writeln!(self.w, "{}", Att(&inst))?,
+            }
         }
+        Ok(())
     }
+}
 
-    fn label_index(&mut self) -> Box<String> {
-        let label = format!(".L{}", self.label_index_counter);
-        self.label_index_counter += 1;
-        Box::new(label)
+// asmのテンプレートを出力する文字列にする
+// 基本asmはそのまま、拡張asmは %0, %1, ... をオペランドで置き換える
+pub(crate) fn asm_text(template: &str, extended: bool, operands: &[String]) -> io::Result<String> {
+    if !extended {
+        return Ok(template.to_string());
     }
+    substitute_asm_operands(template, operands).map_err(|index| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("asm operand number out of range: %{}", index),
+        )
+    })
+}
 
-    fn from_node(&mut self, node: Node) -> io::Result<()> {
-        if let Node::Num(n) = node {
-            writeln!(self.w, "  push {}", n)?;
-            return Ok(());
-        }
+// 拡張asmの "r" オペランドに使うレジスタを registers の先頭から count 個選ぶ
+// clobberに書かれたレジスタは避け、足りなければエラーにする
+pub(crate) fn asm_registers<R: Copy>(
+    registers: &[R],
+    name: impl Fn(R) -> &'static str,
+    clobbers: &[String],
+    count: usize,
+) -> io::Result<Vec<R>> {
+    let available: Vec<R> = registers
+        .iter()
+        .copied()
+        .filter(|r| !clobbers.iter().any(|c| c == name(*r)))
+        .take(count)
+        .collect();
+    if available.len() < count {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "asm needs {} registers but only {} are available",
+                count,
+                available.len()
+            ),
+        ));
+    }
+    Ok(available)
+}
 
-        match node {
-            Node::Num(n) => {
-                writeln!(self.w, "  push {}", n)?;
-                Ok(())
-            }
-            Node::Add { l, r } => {
-                self.from_node(*l)?;
-                self.from_node(*r)?;
-                writeln!(self.w, "  pop rdi")?;
-                writeln!(self.w, "  pop rax")?;
-                writeln!(self.w, "  add rax, rdi")?;
-                writeln!(self.w, "  push rax")?;
-                Ok(())
-            }
-            Node::Sub { l, r } => {
-                self.from_node(*l)?;
-                self.from_node(*r)?;
-                writeln!(self.w, "  pop rdi")?;
-                writeln!(self.w, "  pop rax")?;
-                writeln!(self.w, "  sub rax, rdi")?;
-                writeln!(self.w, "  push rax")?;
-                Ok(())
-            }
-            Node::Mul { l, r } => {
-                self.from_node(*l)?;
-                self.from_node(*r)?;
-                writeln!(self.w, "  pop rdi")?;
-                writeln!(self.w, "  pop rax")?;
-                writeln!(self.w, "  imul rax, rdi")?;
-                writeln!(self.w, "  push rax")?;
-                Ok(())
-            }
-            Node::Div { l, r } => {
-                self.from_node(*l)?;
-                self.from_node(*r)?;
-                writeln!(self.w, "  pop rdi")?;
-                writeln!(self.w, "  pop rax")?;
-                writeln!(self.w, "  cqo")?;
-                writeln!(self.w, "  idiv rdi")?;
-                writeln!(self.w, "  push rax")?;
-                Ok(())
-            }
-            Node::Eq { l, r } => {
-                self.from_node(*l)?;
-                self.from_node(*r)?;
-                writeln!(self.w, "  pop rdi")?;
-                writeln!(self.w, "  pop rax")?;
-                writeln!(self.w, "  cmp rax, rdi")?;
-                writeln!(self.w, "  sete al")?;
-                writeln!(self.w, "  movzb rax, al")?;
-                writeln!(self.w, "  push rax")?;
-                Ok(())
-            }
-            Node::Ne { l, r } => {
-                self.from_node(*l)?;
-                self.from_node(*r)?;
-                writeln!(self.w, "  pop rdi")?;
-                writeln!(self.w, "  pop rax")?;
-                writeln!(self.w, "  cmp rax, rdi")?;
-                writeln!(self.w, "  setne al")?;
-                writeln!(self.w, "  movzb rax, al")?;
-                writeln!(self.w, "  push rax")?;
-                Ok(())
-            }
-            Node::Lt { l, r } => {
-                self.from_node(*l)?;
-                self.from_node(*r)?;
-                writeln!(self.w, "  pop rdi")?;
-                writeln!(self.w, "  pop rax")?;
-                writeln!(self.w, "  cmp rax, rdi")?;
-                writeln!(self.w, "  setl al")?;
-                writeln!(self.w, "  movzb rax, al")?;
-                writeln!(self.w, "  push rax")?;
-                Ok(())
-            }
-            Node::Le { l, r } => {
-                self.from_node(*l)?;
-                self.from_node(*r)?;
-                writeln!(self.w, "  pop rdi")?;
-                writeln!(self.w, "  pop rax")?;
-                writeln!(self.w, "  cmp rax, rdi")?;
-                writeln!(self.w, "  setle al")?;
-                writeln!(self.w, "  movzb rax, al")?;
-                writeln!(self.w, "  push rax")?;
-                Ok(())
-            }
-            Node::Lvar(_) => {
-                self.lval(node)?;
-                writeln!(self.w, "  pop rax")?;
-                writeln!(self.w, "  mov rax, [rax]")?;
-                writeln!(self.w, "  push rax")?;
-                Ok(())
-            }
-            Node::Assign { l, r } => {
-                self.lval(*l)?;
-                self.from_node(*r)?;
-                writeln!(self.w, "  pop rdi")?;
-                writeln!(self.w, "  pop rax")?;
-                writeln!(self.w, "  mov [rax], rdi")?;
-                writeln!(self.w, "  push rdi")?;
-                Ok(())
-            }
-            Node::Return { expr } => {
-                self.from_node(*expr)?;
-                writeln!(self.w, "  pop rax")?;
-                writeln!(self.w, "  mov rsp, rbp")?;
-                writeln!(self.w, "  pop rbp")?;
-                writeln!(self.w, "  ret")?;
-                Ok(())
-            }
-            Node::If { cond, then, els } => {
-                if let Some(els) = els {
-                    let label_index = self.label_index();
-                    self.from_node(*cond)?;
-                    writeln!(self.w, "  pop rax")?;
-                    writeln!(self.w, "  cmp rax, 0")?;
-                    writeln!(self.w, "  je  .Lelse{}", label_index)?;
-                    self.from_node(*then)?;
-                    writeln!(self.w, "  jmp .Lend{}", label_index)?;
-                    writeln!(self.w, ".Lelse{}:", label_index)?;
-                    self.from_node(*els)?;
-                    writeln!(self.w, ".Lend{}:", label_index)?;
-                    Ok(())
-                } else {
-                    let label_index = self.label_index();
-                    self.from_node(*cond)?;
-                    writeln!(self.w, "  pop rax")?;
-                    writeln!(self.w, "  cmp rax, 0")?;
-                    writeln!(self.w, "  je  .Lend{}", label_index)?;
-                    self.from_node(*then)?;
-                    writeln!(self.w, ".Lend{}:", label_index)?;
-                    Ok(())
-                }
-            }
-            Node::While { cond, then } => {
-                let label_index = self.label_index();
-                writeln!(self.w, ".Lbegin{}:", label_index)?;
-                self.from_node(*cond)?;
-                writeln!(self.w, "  pop rax")?;
-                writeln!(self.w, "  cmp rax, 0")?;
-                writeln!(self.w, "  je  .Lend{}", label_index)?;
-                self.from_node(*then)?;
-                writeln!(self.w, "  jmp .Lbegin{}", label_index)?;
-                writeln!(self.w, ".Lend{}:", label_index)?;
-                Ok(())
-            }
-            Node::For { init, cond, step, then } => {
-                if let Some(init) = init {
-                    self.from_node(*init)?;
-                }
-                let label_index = self.label_index();
-                writeln!(self.w, ".Lbegin{}:", label_index)?;
-                if let Some(cond) = cond {
-                    self.from_node(*cond)?;
+// 拡張asmのオペランドの置き場所
+pub(crate) enum AsmPlace<R> {
+    Reg(R),
+    Mem(usize), // 変数のオフセット
+    Imm(i64),
+}
+
+// スタックマシンで式を計算するバックエンド向けに割り当てた拡張asmのオペランド
+pub(crate) struct AsmOperands<R> {
+    pub places: Vec<AsmPlace<R>>,   // %0, %1, ... の置き場所
+    pub loads: Vec<(R, Box<Node>)>, // asmの前に計算してレジスタに載せる式
+    pub stores: Vec<(R, usize)>,    // asmの後で変数に書き戻すレジスタと変数のオフセット
+}
+
+// "r" のオペランドにレジスタを割り当て、"m" は変数、"i" は即値にする
+pub(crate) fn assign_asm_operands<R: Copy>(
+    registers: &[R],
+    name: impl Fn(R) -> &'static str,
+    outputs: Vec<AsmOperand>,
+    inputs: Vec<AsmOperand>,
+    clobbers: &[String],
+) -> io::Result<AsmOperands<R>> {
+    let n_outputs = outputs.len();
+    let count = outputs
+        .iter()
+        .chain(&inputs)
+        .filter(|op| op.constraint.contains('r'))
+        .count();
+    let mut registers = asm_registers(registers, name, clobbers, count)?.into_iter();
+
+    let mut operands = AsmOperands {
+        places: Vec::new(),
+        loads: Vec::new(),
+        stores: Vec::new(),
+    };
+    for (i, AsmOperand { constraint, expr }) in outputs.into_iter().chain(inputs).enumerate() {
+        let is_output = i < n_outputs;
+        let place = match *expr {
+            _ if constraint.contains('r') => {
+                let reg = registers.next().unwrap();
+                if let (true, Node::Lvar(LocalVar { ident: _, offset })) = (is_output, &*expr) {
+                    operands.stores.push((reg, *offset));
                 }
-                writeln!(self.w, "  pop rax")?;
-                writeln!(self.w, "  cmp rax, 0")?;
-                writeln!(self.w, "  je  .Lend{}", label_index)?;
-                self.from_node(*then)?;
-                if let Some(step) = step {
-                    self.from_node(*step)?;
+                if !is_output || constraint.contains('+') {
+                    operands.loads.push((reg, expr));
                 }
-                writeln!(self.w, "  jmp .Lbegin{}", label_index)?;
-                writeln!(self.w, ".Lend{}:", label_index)?;
-                Ok(())
-            }
-            Node::Block { stmts } => {
-                for stmt in stmts {
-                    self.from_node(*stmt)?;
-                    writeln!(self.w, "  pop rax")?;
+                AsmPlace::Reg(reg)
+            }
+            Node::Lvar(LocalVar { ident: _, offset }) => AsmPlace::Mem(offset),
+            _ => match expr.eval_const() {
+                Some(n) => AsmPlace::Imm(n),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("invalid asm operand: {:?}", constraint),
+                    ))
                 }
-                Ok(())
-            }
-        }
+            },
+        };
+        operands.places.push(place);
     }
+    Ok(operands)
 }
@@ -0,0 +1,352 @@
+use std::io::{self, Write};
+
+use crate::ast::node::{AsmOperand, LocalVar, Node, Nodes};
+use crate::gen::{asm_text, assign_asm_operands, AsmOperands, AsmPlace, CodeGen};
+
+// 拡張asmの "r" オペランドに割り当てるレジスタ (AAPCS64の一時レジスタ)
+const ASM_REGISTERS: [&str; 7] = ["x9", "x10", "x11", "x12", "x13", "x14", "x15"];
+
+// 変数 (x29-8 から x29-208) と、returnが無いときの戻り値 (x29-216) の領域
+const RET_OFFSET: usize = 216;
+const FRAME_SIZE: usize = 224; // FIXME: 208 / 8 = 26個しか変数宣言できない
+
+// AArch64 (AAPCS64) 向けのアセンブリを出力する
+// x86-64と同じスタックマシンで、spは常に16バイト境界に揃える必要があるので1要素16バイトで積む
+// 式は値を1つ積み、文は何も積まない
+pub struct Aarch64CodeGen<W: Write> {
+    w: W,
+    label_index_counter: usize,
+}
+
+impl<W: Write> CodeGen<W> for Aarch64CodeGen<W> {
+    fn prefix(&mut self) -> io::Result<()> {
+        writeln!(self.w, ".text")?;
+        writeln!(self.w, ".globl main")?;
+        writeln!(self.w, "main:")?;
+        Ok(())
+    }
+
+    fn prologue(&mut self) -> io::Result<()> {
+        writeln!(self.w, "  stp x29, x30, [sp, #-16]!")?;
+        writeln!(self.w, "  mov x29, sp")?;
+        // 変数の領域の後ろにreturnが無いときの戻り値を置く
+        writeln!(self.w, "  sub sp, sp, #{}", FRAME_SIZE)?;
+        writeln!(self.w, "  stur xzr, [x29, #-{}]", RET_OFFSET)?;
+        Ok(())
+    }
+
+    fn gen_from_nodes(&mut self, nodes: Nodes) -> io::Result<()> {
+        for node in nodes.0 {
+            self.gen_stmt(*node)?;
+        }
+        Ok(())
+    }
+
+    fn epilogue(&mut self) -> io::Result<()> {
+        writeln!(self.w, "  ldur x0, [x29, #-{}]", RET_OFFSET)?;
+        self.ret()
+    }
+}
+
+impl<W: Write> Aarch64CodeGen<W> {
+    pub fn new(w: W) -> Self {
+        Self {
+            w,
+            label_index_counter: 0,
+        }
+    }
+
+    fn push(&mut self, reg: &str) -> io::Result<()> {
+        writeln!(self.w, "  str {}, [sp, #-16]!", reg)
+    }
+
+    fn pop(&mut self, reg: &str) -> io::Result<()> {
+        writeln!(self.w, "  ldr {}, [sp], #16", reg)
+    }
+
+    // x0 を戻り値にして main から戻る
+    fn ret(&mut self) -> io::Result<()> {
+        writeln!(self.w, "  mov sp, x29")?;
+        writeln!(self.w, "  ldp x29, x30, [sp], #16")?;
+        writeln!(self.w, "  ret")
+    }
+
+    // 式の値を捨てる
+    fn gen_discard(&mut self, node: Node) -> io::Result<()> {
+        self.gen_node(node)?;
+        self.pop("x0")
+    }
+
+    fn lval(&mut self, node: Node) -> io::Result<()> {
+        if let Node::Lvar(LocalVar { ident: _, offset }) = node {
+            writeln!(self.w, "  sub x0, x29, #{}", offset)?;
+            self.push("x0")
+        } else {
+            panic!("代入の左辺値が変数ではありません");
+        }
+    }
+
+    fn label_index(&mut self) -> String {
+        let label = format!(".L{}", self.label_index_counter);
+        self.label_index_counter += 1;
+        label
+    }
+
+    // 2つのオペランドを x0 と x1 に取り出す
+    fn binary(&mut self, l: Node, r: Node) -> io::Result<()> {
+        self.gen_node(l)?;
+        self.gen_node(r)?;
+        self.pop("x1")?;
+        self.pop("x0")
+    }
+
+    fn compare(&mut self, l: Node, r: Node, cond: &str) -> io::Result<()> {
+        self.binary(l, r)?;
+        writeln!(self.w, "  cmp x0, x1")?;
+        writeln!(self.w, "  cset x0, {}", cond)?;
+        self.push("x0")
+    }
+
+    fn gen_stmt(&mut self, node: Node) -> io::Result<()> {
+        match node {
+            Node::Return { expr } => {
+                self.gen_node(*expr)?;
+                self.pop("x0")?;
+                self.ret()
+            }
+            Node::If { cond, then, els } => {
+                let label_index = self.label_index();
+                self.gen_node(*cond)?;
+                self.pop("x0")?;
+                if let Some(els) = els {
+                    writeln!(self.w, "  cbz x0, .Lelse{}", label_index)?;
+                    self.gen_stmt(*then)?;
+                    writeln!(self.w, "  b .Lend{}", label_index)?;
+                    writeln!(self.w, ".Lelse{}:", label_index)?;
+                    self.gen_stmt(*els)?;
+                } else {
+                    writeln!(self.w, "  cbz x0, .Lend{}", label_index)?;
+                    self.gen_stmt(*then)?;
+                }
+                writeln!(self.w, ".Lend{}:", label_index)
+            }
+            Node::While { cond, then } => {
+                let label_index = self.label_index();
+                writeln!(self.w, ".Lbegin{}:", label_index)?;
+                self.gen_node(*cond)?;
+                self.pop("x0")?;
+                writeln!(self.w, "  cbz x0, .Lend{}", label_index)?;
+                self.gen_stmt(*then)?;
+                writeln!(self.w, "  b .Lbegin{}", label_index)?;
+                writeln!(self.w, ".Lend{}:", label_index)
+            }
+            Node::For {
+                init,
+                cond,
+                step,
+                then,
+            } => {
+                // 初期化式と更新式は式文ではないので、値を捨てて戻り値を変えない
+                if let Some(init) = init {
+                    self.gen_discard(*init)?;
+                }
+                let label_index = self.label_index();
+                writeln!(self.w, ".Lbegin{}:", label_index)?;
+                // 条件式が無ければ無限ループ
+                if let Some(cond) = cond {
+                    self.gen_node(*cond)?;
+                    self.pop("x0")?;
+                    writeln!(self.w, "  cbz x0, .Lend{}", label_index)?;
+                }
+                self.gen_stmt(*then)?;
+                if let Some(step) = step {
+                    self.gen_discard(*step)?;
+                }
+                writeln!(self.w, "  b .Lbegin{}", label_index)?;
+                writeln!(self.w, ".Lend{}:", label_index)
+            }
+            Node::Block { stmts } => {
+                for stmt in stmts {
+                    self.gen_stmt(*stmt)?;
+                }
+                Ok(())
+            }
+            Node::Asm {
+                template,
+                extended,
+                outputs,
+                inputs,
+                clobbers,
+            } => self.asm(&template, extended, outputs, inputs, &clobbers),
+            // 式文の値はreturnが無いときの戻り値になる
+            expr => {
+                self.gen_node(expr)?;
+                self.pop("x0")?;
+                writeln!(self.w, "  stur x0, [x29, #-{}]", RET_OFFSET)
+            }
+        }
+    }
+
+    fn gen_node(&mut self, node: Node) -> io::Result<()> {
+        match node {
+            Node::Num(n) => {
+                // movの即値は16bitずつ
+                writeln!(self.w, "  mov x0, #{}", n & 0xffff)?;
+                if n > 0xffff {
+                    writeln!(self.w, "  movk x0, #{}, lsl #16", n >> 16)?;
+                }
+                self.push("x0")
+            }
+            Node::Add { l, r } => {
+                self.binary(*l, *r)?;
+                writeln!(self.w, "  add x0, x0, x1")?;
+                self.push("x0")
+            }
+            Node::Sub { l, r } => {
+                self.binary(*l, *r)?;
+                writeln!(self.w, "  sub x0, x0, x1")?;
+                self.push("x0")
+            }
+            Node::Mul { l, r } => {
+                self.binary(*l, *r)?;
+                writeln!(self.w, "  mul x0, x0, x1")?;
+                self.push("x0")
+            }
+            Node::Div { l, r } => {
+                self.binary(*l, *r)?;
+                writeln!(self.w, "  sdiv x0, x0, x1")?;
+                self.push("x0")
+            }
+            Node::Eq { l, r } => self.compare(*l, *r, "eq"),
+            Node::Ne { l, r } => self.compare(*l, *r, "ne"),
+            Node::Lt { l, r } => self.compare(*l, *r, "lt"),
+            Node::Le { l, r } => self.compare(*l, *r, "le"),
+            Node::Lvar(_) => {
+                self.lval(node)?;
+                self.pop("x0")?;
+                writeln!(self.w, "  ldr x0, [x0]")?;
+                self.push("x0")
+            }
+            Node::Assign { l, r } => {
+                self.lval(*l)?;
+                self.gen_node(*r)?;
+                self.pop("x1")?;
+                self.pop("x0")?;
+                writeln!(self.w, "  str x1, [x0]")?;
+                self.push("x1")
+            }
+            stmt => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("statement used as an expression: {}", stmt),
+            )),
+        }
+    }
+
+    fn asm(
+        &mut self,
+        template: &str,
+        extended: bool,
+        outputs: Vec<AsmOperand>,
+        inputs: Vec<AsmOperand>,
+        clobbers: &[String],
+    ) -> io::Result<()> {
+        let AsmOperands {
+            places,
+            loads,
+            stores,
+        } = assign_asm_operands(&ASM_REGISTERS, |r| r, outputs, inputs, clobbers)?;
+
+        // レジスタに載せる値を積んでから、逆順に取り出す
+        let mut regs = Vec::new();
+        for (reg, expr) in loads {
+            self.gen_node(*expr)?;
+            regs.push(reg);
+        }
+        for reg in regs.into_iter().rev() {
+            self.pop(reg)?;
+        }
+
+        let operands: Vec<String> = places
+            .into_iter()
+            .map(|place| match place {
+                AsmPlace::Reg(reg) => reg.to_string(),
+                AsmPlace::Mem(offset) => format!("[x29, #-{}]", offset),
+                AsmPlace::Imm(n) => format!("#{}", n),
+            })
+            .collect();
+        writeln!(self.w, "{}", asm_text(template, extended, &operands)?)?;
+
+        // 出力オペランドをレジスタから変数に書き戻す
+        for (reg, offset) in stores {
+            writeln!(self.w, "  stur {}, [x29, #-{}]", reg, offset)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::parser::Parser;
+    use crate::lexer::tokenize;
+
+    use super::*;
+
+    #[test]
+    fn test_gen() {
+        let tokens =
+            tokenize(&mut "a = 70000; if (a > 1) return a / 2;".chars().peekable()).unwrap();
+        let nodes = Parser::new(tokens.iter()).parse().unwrap();
+
+        let mut out = Vec::new();
+        let mut gen = Aarch64CodeGen::new(&mut out);
+        gen.gen_from_nodes(nodes).unwrap();
+
+        let expected = [
+            // a = 70000;
+            "  sub x0, x29, #8",
+            "  str x0, [sp, #-16]!",
+            "  mov x0, #4464",
+            "  movk x0, #1, lsl #16",
+            "  str x0, [sp, #-16]!",
+            "  ldr x1, [sp], #16",
+            "  ldr x0, [sp], #16",
+            "  str x1, [x0]",
+            "  str x1, [sp, #-16]!",
+            "  ldr x0, [sp], #16",
+            "  stur x0, [x29, #-216]",
+            // if (1 < a)
+            "  mov x0, #1",
+            "  str x0, [sp, #-16]!",
+            "  sub x0, x29, #8",
+            "  str x0, [sp, #-16]!",
+            "  ldr x0, [sp], #16",
+            "  ldr x0, [x0]",
+            "  str x0, [sp, #-16]!",
+            "  ldr x1, [sp], #16",
+            "  ldr x0, [sp], #16",
+            "  cmp x0, x1",
+            "  cset x0, lt",
+            "  str x0, [sp, #-16]!",
+            "  ldr x0, [sp], #16",
+            "  cbz x0, .Lend.L0",
+            // return a / 2;
+            "  sub x0, x29, #8",
+            "  str x0, [sp, #-16]!",
+            "  ldr x0, [sp], #16",
+            "  ldr x0, [x0]",
+            "  str x0, [sp, #-16]!",
+            "  mov x0, #2",
+            "  str x0, [sp, #-16]!",
+            "  ldr x1, [sp], #16",
+            "  ldr x0, [sp], #16",
+            "  sdiv x0, x0, x1",
+            "  str x0, [sp, #-16]!",
+            "  ldr x0, [sp], #16",
+            "  mov sp, x29",
+            "  ldp x29, x30, [sp], #16",
+            "  ret",
+            ".Lend.L0:",
+        ];
+        assert_eq!(String::from_utf8(out).unwrap(), expected.join("\n") + "\n");
+    }
+}
@@ -0,0 +1,402 @@
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+use crate::ast::node::{AsmOperand, LocalVar, Node, Nodes};
+use crate::gen::CodeGen;
+
+// 括弧の付け方
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Parens {
+    Minimal, // 優先順位と結合規則から必要なところだけ
+    Full,    // 部分式をすべて括弧で囲む
+}
+
+// ASTからCのソースを出力する
+// 出力は rust9cc でそのままパースでき、パースし直すと同じASTになる
+// ただし else の付いた if の then 節が else の無い if で終わるときは、
+// ぶら下がりelseを避けるためにブロックで囲むので Node::Block が増える
+pub struct CSourceCodeGen<W: Write> {
+    w: W,
+    parens: Parens,
+}
+
+impl<W: Write> CodeGen<W> for CSourceCodeGen<W> {
+    fn prefix(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn prologue(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn gen_from_nodes(&mut self, nodes: Nodes) -> io::Result<()> {
+        let mut printer = Printer {
+            out: String::new(),
+            parens: self.parens,
+        };
+        for node in &nodes.0 {
+            printer.stmt(node, 0);
+        }
+        write!(self.w, "{}", printer.out)
+    }
+
+    fn epilogue(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<W: Write> CSourceCodeGen<W> {
+    pub fn new(w: W, parens: Parens) -> Self {
+        Self { w, parens }
+    }
+}
+
+struct Printer {
+    out: String,
+    parens: Parens,
+}
+
+// 二項演算子の優先順位 (大きいほど強く結合する)
+fn precedence(node: &Node) -> u8 {
+    match node {
+        Node::Assign { .. } => 1,
+        Node::Eq { .. } | Node::Ne { .. } => 2,
+        Node::Lt { .. } | Node::Le { .. } => 3,
+        Node::Add { .. } | Node::Sub { .. } => 4,
+        Node::Mul { .. } | Node::Div { .. } => 5,
+        _ => 6,
+    }
+}
+
+// 文の末尾が else の無い if で終わっているか
+fn ends_with_open_if(node: &Node) -> bool {
+    match node {
+        Node::If { els: None, .. } => true,
+        Node::If { els: Some(els), .. } => ends_with_open_if(els),
+        Node::While { then, .. } | Node::For { then, .. } => ends_with_open_if(then),
+        _ => false,
+    }
+}
+
+// 文字列リテラルとして書けるようにエスケープする
+fn quote(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            '\0' => out.push_str("\\0"),
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl Printer {
+    fn indent(&mut self, depth: usize) {
+        self.out.push_str(&"    ".repeat(depth));
+    }
+
+    fn stmt(&mut self, node: &Node, depth: usize) {
+        self.indent(depth);
+        self.stmt_body(node, depth);
+    }
+
+    // 字下げの後ろに文を書く
+    fn stmt_body(&mut self, node: &Node, depth: usize) {
+        match node {
+            Node::Return { expr } => {
+                self.out.push_str("return ");
+                self.expr(expr);
+                self.out.push_str(";\n");
+            }
+            Node::If { cond, then, els } => {
+                self.out.push_str("if (");
+                self.expr(cond);
+                self.out.push(')');
+                match els {
+                    Some(els) if ends_with_open_if(then) => {
+                        self.out.push_str(" {\n");
+                        self.stmt(then, depth + 1);
+                        self.indent(depth);
+                        self.out.push('}');
+                        self.els(els, depth);
+                    }
+                    Some(els) => {
+                        let block = self.substmt(then, depth);
+                        if block {
+                            self.out.push(' ');
+                        } else {
+                            self.indent(depth);
+                        }
+                        self.els(els, depth);
+                    }
+                    None => {
+                        if self.substmt(then, depth) {
+                            self.out.push('\n');
+                        }
+                    }
+                }
+            }
+            Node::While { cond, then } => {
+                self.out.push_str("while (");
+                self.expr(cond);
+                self.out.push(')');
+                if self.substmt(then, depth) {
+                    self.out.push('\n');
+                }
+            }
+            Node::For {
+                init,
+                cond,
+                step,
+                then,
+            } => {
+                self.out.push_str("for (");
+                if let Some(init) = init {
+                    self.expr(init);
+                }
+                self.out.push(';');
+                if let Some(cond) = cond {
+                    self.out.push(' ');
+                    self.expr(cond);
+                }
+                self.out.push(';');
+                if let Some(step) = step {
+                    self.out.push(' ');
+                    self.expr(step);
+                }
+                self.out.push(')');
+                if self.substmt(then, depth) {
+                    self.out.push('\n');
+                }
+            }
+            Node::Block { stmts } => {
+                self.block(stmts, depth);
+                self.out.push('\n');
+            }
+            Node::Asm {
+                template,
+                extended,
+                outputs,
+                inputs,
+                clobbers,
+            } => {
+                self.out.push_str("asm(");
+                self.out.push_str(&quote(template));
+                if *extended {
+                    self.asm_operands(outputs);
+                    self.asm_operands(inputs);
+                    self.out.push_str(" :");
+                    for (i, clobber) in clobbers.iter().enumerate() {
+                        self.out.push_str(if i == 0 { " " } else { ", " });
+                        self.out.push_str(&quote(clobber));
+                    }
+                }
+                self.out.push_str(");\n");
+            }
+            expr => {
+                self.expr(expr);
+                self.out.push_str(";\n");
+            }
+        }
+    }
+
+    // if/while/for の本体を書く
+    // ブロックなら閉じ括弧の直後で止めて true を返し、それ以外は次の行に字下げして書く
+    fn substmt(&mut self, node: &Node, depth: usize) -> bool {
+        if let Node::Block { stmts } = node {
+            self.out.push(' ');
+            self.block(stmts, depth);
+            true
+        } else {
+            self.out.push('\n');
+            self.stmt(node, depth + 1);
+            false
+        }
+    }
+
+    fn els(&mut self, els: &Node, depth: usize) {
+        self.out.push_str("else");
+        // else if は字下げを深くしない
+        if let Node::If { .. } = els {
+            self.out.push(' ');
+            self.stmt_body(els, depth);
+        } else if self.substmt(els, depth) {
+            self.out.push('\n');
+        }
+    }
+
+    fn block(&mut self, stmts: &[Box<Node>], depth: usize) {
+        self.out.push_str("{\n");
+        for stmt in stmts {
+            self.stmt(stmt, depth + 1);
+        }
+        self.indent(depth);
+        self.out.push('}');
+    }
+
+    fn asm_operands(&mut self, operands: &[AsmOperand]) {
+        self.out.push_str(" :");
+        for (i, AsmOperand { constraint, expr }) in operands.iter().enumerate() {
+            self.out.push_str(if i == 0 { " " } else { ", " });
+            self.out.push_str(&quote(constraint));
+            self.out.push('(');
+            self.expr(expr);
+            self.out.push(')');
+        }
+    }
+
+    fn expr(&mut self, node: &Node) {
+        let (op, l, r) = match node {
+            Node::Num(n) => {
+                write!(self.out, "{}", n).unwrap();
+                return;
+            }
+            Node::Lvar(LocalVar { ident, offset: _ }) => {
+                self.out.push_str(ident);
+                return;
+            }
+            Node::Assign { l, r } => ("=", l, r),
+            Node::Add { l, r } => ("+", l, r),
+            Node::Sub { l, r } => ("-", l, r),
+            Node::Mul { l, r } => ("*", l, r),
+            Node::Div { l, r } => ("/", l, r),
+            Node::Lt { l, r } => ("<", l, r),
+            Node::Le { l, r } => ("<=", l, r),
+            Node::Eq { l, r } => ("==", l, r),
+            Node::Ne { l, r } => ("!=", l, r),
+            stmt => panic!("式ではありません: {}", stmt),
+        };
+
+        // 代入は右結合、それ以外は左結合
+        let prec = precedence(node);
+        let (l_parens, r_parens) = match (self.parens, node) {
+            (Parens::Full, _) => (precedence(l) < 6, precedence(r) < 6),
+            (Parens::Minimal, Node::Assign { .. }) => (precedence(l) <= prec, precedence(r) < prec),
+            (Parens::Minimal, _) => (precedence(l) < prec, precedence(r) <= prec),
+        };
+        self.operand(l, l_parens);
+        write!(self.out, " {} ", op).unwrap();
+        self.operand(r, r_parens);
+    }
+
+    fn operand(&mut self, node: &Node, parens: bool) {
+        if parens {
+            self.out.push('(');
+            self.expr(node);
+            self.out.push(')');
+        } else {
+            self.expr(node);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::parser::Parser;
+    use crate::lexer::tokenize;
+
+    use super::*;
+
+    fn parse(src: &str) -> Nodes {
+        let tokens = tokenize(&mut src.chars().peekable()).unwrap();
+        Parser::new(tokens.iter()).parse().unwrap()
+    }
+
+    fn print(nodes: Nodes, parens: Parens) -> String {
+        let mut out = Vec::new();
+        CSourceCodeGen::new(&mut out, parens)
+            .gen_from_nodes(nodes)
+            .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_print() {
+        struct Test {
+            name: &'static str,
+            input: &'static str,
+            parens: Parens,
+            expected: &'static str,
+        }
+
+        let tests = vec![
+            Test {
+                name: "minimal parentheses",
+                input: "a = (1 + 2) * 3 - (4 - 5) - 6 / (7 * 8); b = a = (1 < 2) == 1;",
+                parens: Parens::Minimal,
+                expected: "a = (1 + 2) * 3 - (4 - 5) - 6 / (7 * 8);\nb = a = 1 < 2 == 1;\n",
+            },
+            Test {
+                name: "full parentheses",
+                input: "a = 1 + 2 * 3; b = a = 1 < 2 == 1;",
+                parens: Parens::Full,
+                expected: "a = (1 + (2 * 3));\nb = (a = ((1 < 2) == 1));\n",
+            },
+            Test {
+                name: "greater than",
+                input: "return -a > b;",
+                parens: Parens::Minimal,
+                expected: "return b < 0 - a;\n",
+            },
+            Test {
+                name: "control flow",
+                input: "for (i = 0; i < 10; i = i + 1) { if (i == 3) x = i; else if (i == 4) { return i; } else while (x) x = x - 1; } for (;;) return 1;",
+                parens: Parens::Minimal,
+                expected: concat!(
+                    "for (i = 0; i < 10; i = i + 1) {\n",
+                    "    if (i == 3)\n",
+                    "        x = i;\n",
+                    "    else if (i == 4) {\n",
+                    "        return i;\n",
+                    "    } else\n",
+                    "        while (x)\n",
+                    "            x = x - 1;\n",
+                    "}\n",
+                    "for (;;)\n",
+                    "    return 1;\n",
+                ),
+            },
+            Test {
+                name: "dangling else",
+                input: "if (a) { if (b) return 1; } else return 2;",
+                parens: Parens::Minimal,
+                expected: "if (a) {\n    if (b)\n        return 1;\n} else\n    return 2;\n",
+            },
+            Test {
+                name: "asm",
+                input: r#"asm("mov %0, %1\n\t\"x\"" : "=r"(y) : "r"(x), "i"(5) : "rax", "memory");"#,
+                parens: Parens::Minimal,
+                expected: "asm(\"mov %0, %1\\n\\t\\\"x\\\"\" : \"=r\"(y) : \"r\"(x), \"i\"(5) : \"rax\", \"memory\");\n",
+            },
+        ];
+
+        for t in tests {
+            assert_eq!(print(parse(t.input), t.parens), t.expected, "Failed in the {}", t.name);
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let inputs = [
+            "1 - (2 - 3) - 4; 1 / (2 * 3) * 4; a = b = 3 + 4 * 5 - 6 / 7;",
+            "x = -y + +3; return x >= 2 != (y <= 1 == 0);",
+            "if (1) if (2) x = 1; else x = 2; else if (3) x = 3; else { x = 4; y = 5; }",
+            "while (i < 10) { i = i + 1; } for (;;) {} for (i = 0; ; ) if (i) return i;",
+            r#"asm("nop"); asm volatile ("add %0, %1" : "+r"(x) : "i"(2 * 3) : "cc");"#,
+            "_Static_assert(1, \"one\"); { { } }",
+        ];
+
+        for input in inputs {
+            for parens in [Parens::Minimal, Parens::Full] {
+                let printed = print(parse(input), parens);
+                assert_eq!(parse(&printed).0, parse(input).0, "Failed in the {}", printed);
+            }
+        }
+    }
+}
@@ -0,0 +1,356 @@
+use std::io::{self, Write};
+
+use crate::ast::node::Nodes;
+use crate::gen::encode::encode;
+use crate::gen::x86_64::Selector;
+use crate::gen::CodeGen;
+use crate::ir;
+
+const EHDR_SIZE: usize = 64;
+const SHDR_SIZE: usize = 64;
+const SYM_SIZE: usize = 24;
+const RELA_SIZE: usize = 24;
+
+const ET_REL: u16 = 1;
+const EM_X86_64: u16 = 62;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const SHF_INFO_LINK: u64 = 0x40;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+
+const R_X86_64_PC32: u32 = 2;
+const R_X86_64_PLT32: u32 = 4;
+
+// セクションヘッダの並び
+const TEXT_INDEX: u16 = 1;
+const SYMTAB_INDEX: u32 = 3;
+const STRTAB_INDEX: u32 = 4;
+const SHSTRTAB_INDEX: u16 = 5;
+const SECTION_NAMES: [&str; 7] = [
+    "",
+    ".text",
+    ".rela.text",
+    ".symtab",
+    ".strtab",
+    ".shstrtab",
+    ".note.GNU-stack",
+];
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RelocationKind {
+    Pc32,
+    Plt32,
+}
+
+impl RelocationKind {
+    fn r_type(self) -> u32 {
+        match self {
+            RelocationKind::Pc32 => R_X86_64_PC32,
+            RelocationKind::Plt32 => R_X86_64_PLT32,
+        }
+    }
+}
+
+// .text中の offset にある4バイトを symbol + addend - (書き込み位置) で埋める
+#[derive(Debug, PartialEq, Clone)]
+pub struct Relocation {
+    pub offset: u64,
+    pub symbol: String,
+    pub kind: RelocationKind,
+    pub addend: i64,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub offset: u64,
+    pub size: u64,
+    pub global: bool,
+    pub defined: bool, // falseなら他のオブジェクトで定義される
+}
+
+// .textセクションだけを持つELF64の再配置可能オブジェクト
+#[derive(Debug, PartialEq, Clone)]
+pub struct ObjectFile {
+    pub text: Vec<u8>,
+    pub symbols: Vec<Symbol>,
+    pub relocations: Vec<Relocation>,
+}
+
+impl ObjectFile {
+    pub fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        // 参照だけされているシンボルは未定義のグローバルシンボルにする
+        let mut symbols = self.symbols.clone();
+        for r in &self.relocations {
+            if !symbols.iter().any(|s| s.name == r.symbol) {
+                symbols.push(Symbol {
+                    name: r.symbol.clone(),
+                    offset: 0,
+                    size: 0,
+                    global: true,
+                    defined: false,
+                });
+            }
+        }
+        // シンボルテーブルはローカルシンボルを先に並べる必要がある
+        symbols.sort_by_key(|s| s.global);
+
+        let mut strtab = vec![0];
+        let mut symtab = vec![0; SYM_SIZE];
+        // .textのセクションシンボル
+        put_symbol(&mut symtab, 0, STB_LOCAL, STT_SECTION, TEXT_INDEX, 0, 0);
+        let first_global = 2 + symbols.iter().filter(|s| !s.global).count();
+        for s in &symbols {
+            let name = strtab.len() as u32;
+            strtab.extend_from_slice(s.name.as_bytes());
+            strtab.push(0);
+            let (bind, typ, shndx) = match (s.global, s.defined) {
+                (true, true) => (STB_GLOBAL, STT_FUNC, TEXT_INDEX),
+                (true, false) => (STB_GLOBAL, STT_NOTYPE, 0),
+                (false, _) => (STB_LOCAL, STT_FUNC, TEXT_INDEX),
+            };
+            put_symbol(&mut symtab, name, bind, typ, shndx, s.offset, s.size);
+        }
+
+        let mut rela = Vec::new();
+        for r in &self.relocations {
+            let index = 2 + symbols.iter().position(|s| s.name == r.symbol).unwrap();
+            put_u64(&mut rela, r.offset);
+            put_u64(&mut rela, ((index as u64) << 32) | r.kind.r_type() as u64);
+            put_u64(&mut rela, r.addend as u64);
+        }
+
+        let mut shstrtab = Vec::new();
+        let mut names = Vec::new();
+        for name in SECTION_NAMES {
+            names.push(shstrtab.len() as u32);
+            shstrtab.extend_from_slice(name.as_bytes());
+            shstrtab.push(0);
+        }
+
+        // ファイル上の配置
+        let text_offset = EHDR_SIZE;
+        let rela_offset = align(text_offset + self.text.len(), 8);
+        let symtab_offset = rela_offset + rela.len();
+        let strtab_offset = symtab_offset + symtab.len();
+        let shstrtab_offset = strtab_offset + strtab.len();
+        let shdr_offset = align(shstrtab_offset + shstrtab.len(), 8);
+
+        let mut out = Vec::new();
+        put_elf_header(&mut out, ET_REL, 0, 0, shdr_offset as u64, 0, SECTION_NAMES.len() as u16);
+        out.extend_from_slice(&self.text);
+        out.resize(rela_offset, 0);
+        out.extend_from_slice(&rela);
+        out.extend_from_slice(&symtab);
+        out.extend_from_slice(&strtab);
+        out.extend_from_slice(&shstrtab);
+        out.resize(shdr_offset, 0);
+
+        let headers = [
+            SectionHeader::default(),
+            SectionHeader {
+                name: names[1],
+                typ: SHT_PROGBITS,
+                flags: SHF_ALLOC | SHF_EXECINSTR,
+                offset: text_offset as u64,
+                size: self.text.len() as u64,
+                addralign: 16,
+                ..Default::default()
+            },
+            SectionHeader {
+                name: names[2],
+                typ: SHT_RELA,
+                flags: SHF_INFO_LINK,
+                offset: rela_offset as u64,
+                size: rela.len() as u64,
+                link: SYMTAB_INDEX,
+                info: TEXT_INDEX as u32,
+                addralign: 8,
+                entsize: RELA_SIZE as u64,
+                ..Default::default()
+            },
+            SectionHeader {
+                name: names[3],
+                typ: SHT_SYMTAB,
+                offset: symtab_offset as u64,
+                size: symtab.len() as u64,
+                link: STRTAB_INDEX,
+                info: first_global as u32,
+                addralign: 8,
+                entsize: SYM_SIZE as u64,
+                ..Default::default()
+            },
+            SectionHeader {
+                name: names[4],
+                typ: SHT_STRTAB,
+                offset: strtab_offset as u64,
+                size: strtab.len() as u64,
+                addralign: 1,
+                ..Default::default()
+            },
+            SectionHeader {
+                name: names[5],
+                typ: SHT_STRTAB,
+                offset: shstrtab_offset as u64,
+                size: shstrtab.len() as u64,
+                addralign: 1,
+                ..Default::default()
+            },
+            // スタックを実行可能にしないためのマーカー
+            SectionHeader {
+                name: names[6],
+                typ: SHT_PROGBITS,
+                offset: shdr_offset as u64,
+                addralign: 1,
+                ..Default::default()
+            },
+        ];
+        for header in headers {
+            header.write(&mut out);
+        }
+
+        w.write_all(&out)
+    }
+}
+
+#[derive(Default)]
+struct SectionHeader {
+    name: u32,
+    typ: u32,
+    flags: u64,
+    addr: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    addralign: u64,
+    entsize: u64,
+}
+
+impl SectionHeader {
+    fn write(&self, out: &mut Vec<u8>) {
+        put_u32(out, self.name);
+        put_u32(out, self.typ);
+        put_u64(out, self.flags);
+        put_u64(out, self.addr);
+        put_u64(out, self.offset);
+        put_u64(out, self.size);
+        put_u32(out, self.link);
+        put_u32(out, self.info);
+        put_u64(out, self.addralign);
+        put_u64(out, self.entsize);
+    }
+}
+
+pub(crate) fn put_elf_header(
+    out: &mut Vec<u8>,
+    typ: u16,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    phnum: u16,
+    shnum: u16,
+) {
+    // ELF64, リトルエンディアン, バージョン1, System V ABI
+    out.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+    out.extend_from_slice(&[0; 8]);
+    put_u16(out, typ);
+    put_u16(out, EM_X86_64);
+    put_u32(out, 1);
+    put_u64(out, entry);
+    put_u64(out, phoff);
+    put_u64(out, shoff);
+    put_u32(out, 0);
+    put_u16(out, EHDR_SIZE as u16);
+    put_u16(out, if phnum > 0 { 56 } else { 0 });
+    put_u16(out, phnum);
+    put_u16(out, if shnum > 0 { SHDR_SIZE as u16 } else { 0 });
+    put_u16(out, shnum);
+    put_u16(out, if shnum > 0 { SHSTRTAB_INDEX } else { 0 });
+}
+
+fn put_symbol(out: &mut Vec<u8>, name: u32, bind: u8, typ: u8, shndx: u16, value: u64, size: u64) {
+    put_u32(out, name);
+    out.push((bind << 4) | typ);
+    out.push(0);
+    put_u16(out, shndx);
+    put_u64(out, value);
+    put_u64(out, size);
+}
+
+pub(crate) fn put_u16(out: &mut Vec<u8>, n: u16) {
+    out.extend_from_slice(&n.to_le_bytes());
+}
+
+pub(crate) fn put_u32(out: &mut Vec<u8>, n: u32) {
+    out.extend_from_slice(&n.to_le_bytes());
+}
+
+pub(crate) fn put_u64(out: &mut Vec<u8>, n: u64) {
+    out.extend_from_slice(&n.to_le_bytes());
+}
+
+pub(crate) fn align(n: usize, align: usize) -> usize {
+    n.div_ceil(align) * align
+}
+
+// 外部のアセンブラを使わずにELFの再配置可能オブジェクトを出力する
+pub struct ElfCodeGen<W: Write> {
+    w: W,
+    selector: Selector,
+}
+
+impl<W: Write> CodeGen<W> for ElfCodeGen<W> {
+    fn prefix(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn prologue(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn gen_from_nodes(&mut self, nodes: Nodes) -> io::Result<()> {
+        self.selector.gen_function(&ir::lower(&nodes))?;
+        Ok(())
+    }
+
+    fn epilogue(&mut self) -> io::Result<()> {
+        let assembled = encode(&self.selector.take())?;
+        let object = ObjectFile {
+            symbols: vec![Symbol {
+                name: "main".to_string(),
+                offset: 0,
+                size: assembled.code.len() as u64,
+                global: true,
+                defined: true,
+            }],
+            text: assembled.code,
+            relocations: assembled.relocations,
+        };
+        object.write(&mut self.w)
+    }
+}
+
+impl<W: Write> ElfCodeGen<W> {
+    pub fn new(w: W) -> Self {
+        Self::with_opt_level(w, 0)
+    }
+
+    pub fn with_opt_level(w: W, opt_level: u8) -> Self {
+        Self {
+            w,
+            selector: Selector::with_opt_level(opt_level),
+        }
+    }
+}
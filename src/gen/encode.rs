@@ -0,0 +1,308 @@
+use std::collections::HashMap;
+use std::io;
+
+use crate::gen::elf::{Relocation, RelocationKind};
+use crate::gen::x86_64::{Cond, Inst, Operand, Reg};
+
+// 命令列を機械語に変換した結果
+#[derive(Debug, PartialEq)]
+pub struct Assembled {
+    pub code: Vec<u8>,
+    pub relocations: Vec<Relocation>,
+}
+
+// x86-64の命令列を機械語に変換する
+// ジャンプは常にrel32で出力するので、ラベルの位置は1パスで決まる
+pub fn encode(insts: &[Inst]) -> io::Result<Assembled> {
+    let mut encoder = Encoder::default();
+    for inst in insts {
+        encoder.inst(inst)?;
+    }
+
+    for (pos, label) in encoder.fixups {
+        let target = *encoder.labels.get(&label).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("undefined label: {}", label))
+        })?;
+        let rel = target as i64 - (pos as i64 + 4);
+        encoder.code[pos..pos + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+    }
+
+    Ok(Assembled {
+        code: encoder.code,
+        relocations: encoder.relocations,
+    })
+}
+
+#[derive(Default)]
+struct Encoder {
+    code: Vec<u8>,
+    labels: HashMap<String, usize>,
+    fixups: Vec<(usize, String)>, // (rel32を書き込む位置, ラベル)
+    relocations: Vec<Relocation>,
+}
+
+impl Encoder {
+    fn inst(&mut self, inst: &Inst) -> io::Result<()> {
+        match inst {
+            Inst::Push(Operand::Reg(r)) => {
+                self.rex(false, 0, r.number());
+                self.code.push(0x50 + (r.number() & 7));
+            }
+            Inst::Push(Operand::Imm(n)) => {
+                self.code.push(0x68);
+                self.imm32(*n)?;
+            }
+            Inst::Pop(r) => {
+                self.rex(false, 0, r.number());
+                self.code.push(0x58 + (r.number() & 7));
+            }
+            Inst::Mov(Operand::Reg(dst), Operand::Reg(src)) => self.reg_reg(&[0x89], *src, *dst),
+            Inst::Mov(Operand::Reg(dst), Operand::Imm(n)) => {
+                if i32::try_from(*n).is_ok() {
+                    self.reg_reg(&[0xc7], Reg::Rax, *dst);
+                    self.imm32(*n)?;
+                } else {
+                    // movabs dst, imm64
+                    self.rex(true, 0, dst.number());
+                    self.code.push(0xb8 + (dst.number() & 7));
+                    self.code.extend_from_slice(&n.to_le_bytes());
+                }
+            }
+            Inst::Mov(Operand::Reg(dst), Operand::Mem { base, disp }) => {
+                self.reg_mem(&[0x8b], *dst, *base, *disp)
+            }
+            Inst::Mov(Operand::Mem { base, disp }, Operand::Reg(src)) => {
+                self.reg_mem(&[0x89], *src, *base, *disp)
+            }
+            Inst::Mov(Operand::Mem { base, disp }, Operand::Imm(n)) => {
+                self.reg_mem(&[0xc7], Reg::Rax, *base, *disp);
+                self.imm32(*n)?;
+            }
+            Inst::Add(dst, Operand::Reg(src)) => self.reg_reg(&[0x01], *src, *dst),
+            Inst::Add(dst, Operand::Imm(n)) => self.alu_imm(0, *dst, *n)?,
+            Inst::Sub(dst, Operand::Reg(src)) => self.reg_reg(&[0x29], *src, *dst),
+            Inst::Sub(dst, Operand::Imm(n)) => self.alu_imm(5, *dst, *n)?,
+            Inst::Cmp(l, Operand::Reg(r)) => self.reg_reg(&[0x39], *r, *l),
+            Inst::Cmp(l, Operand::Imm(n)) => self.alu_imm(7, *l, *n)?,
+            Inst::Imul(dst, src) => self.reg_reg(&[0x0f, 0xaf], *dst, *src),
+            Inst::Cqo => self.code.extend_from_slice(&[0x48, 0x99]),
+            // idiv r/m64 は /7
+            Inst::Idiv(r) => self.reg_reg(&[0xf7], Reg::Rdi, *r),
+            Inst::Set(cond) => {
+                let op = match cond {
+                    Cond::E => 0x94,
+                    Cond::Ne => 0x95,
+                    Cond::L => 0x9c,
+                    Cond::Le => 0x9e,
+                };
+                self.code.extend_from_slice(&[0x0f, op, 0xc0]);
+            }
+            Inst::Movzb(r) => self.reg_reg(&[0x0f, 0xb6], *r, Reg::Rax),
+            Inst::Jmp(label) => {
+                self.code.push(0xe9);
+                self.rel32(label);
+            }
+            Inst::Je(label) => {
+                self.code.extend_from_slice(&[0x0f, 0x84]);
+                self.rel32(label);
+            }
+            Inst::Call(symbol) => {
+                self.code.push(0xe8);
+                self.relocations.push(Relocation {
+                    offset: self.code.len() as u64,
+                    symbol: symbol.clone(),
+                    kind: RelocationKind::Plt32,
+                    addend: -4,
+                });
+                self.code.extend_from_slice(&[0; 4]);
+            }
+            Inst::Label(label) => {
+                self.labels.insert(label.clone(), self.code.len());
+            }
+            Inst::Ret => self.code.push(0xc3),
+            Inst::Syscall => self.code.extend_from_slice(&[0x0f, 0x05]),
+            Inst::InlineAsm { .. } => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "inline asm cannot be encoded without an assembler",
+                ))
+            }
+            other => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("cannot encode instruction: {}", other.to_string().trim()),
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    // REXプレフィックス
+    // 64bitオペランドか拡張レジスタ(r8-r15)を使うときだけ出力する
+    fn rex(&mut self, w: bool, reg: u8, rm: u8) {
+        let rex = 0x40 | ((w as u8) << 3) | ((reg >> 3) << 2) | (rm >> 3);
+        if rex != 0x40 {
+            self.code.push(rex);
+        }
+    }
+
+    // op reg, rm (どちらもレジスタ、64bit)
+    fn reg_reg(&mut self, opcode: &[u8], reg: Reg, rm: Reg) {
+        self.rex(true, reg.number(), rm.number());
+        self.code.extend_from_slice(opcode);
+        self.code
+            .push(0xc0 | ((reg.number() & 7) << 3) | (rm.number() & 7));
+    }
+
+    // op reg, [base+disp] (64bit)
+    fn reg_mem(&mut self, opcode: &[u8], reg: Reg, base: Reg, disp: i32) {
+        self.rex(true, reg.number(), base.number());
+        self.code.extend_from_slice(opcode);
+
+        let reg = (reg.number() & 7) << 3;
+        let rm = base.number() & 7;
+        // rbpとr13はdisp無しのエンコーディングが使えない
+        let mode = if disp == 0 && rm != 5 {
+            0x00
+        } else if i8::try_from(disp).is_ok() {
+            0x40
+        } else {
+            0x80
+        };
+        self.code.push(mode | reg | rm);
+        // rspとr12はSIBバイトが必要
+        if rm == 4 {
+            self.code.push(0x24);
+        }
+        match mode {
+            0x40 => self.code.push(disp as i8 as u8),
+            0x80 => self.code.extend_from_slice(&disp.to_le_bytes()),
+            _ => {}
+        }
+    }
+
+    // add/sub/cmp r/m64, imm32 (0x81 /ext)
+    fn alu_imm(&mut self, ext: u8, rm: Reg, n: i64) -> io::Result<()> {
+        self.rex(true, 0, rm.number());
+        self.code.push(0x81);
+        self.code.push(0xc0 | (ext << 3) | (rm.number() & 7));
+        self.imm32(n)
+    }
+
+    fn imm32(&mut self, n: i64) -> io::Result<()> {
+        let n = i32::try_from(n).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("immediate out of range: {}", n))
+        })?;
+        self.code.extend_from_slice(&n.to_le_bytes());
+        Ok(())
+    }
+
+    fn rel32(&mut self, label: &str) {
+        self.fixups.push((self.code.len(), label.to_string()));
+        self.code.extend_from_slice(&[0; 4]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        struct Test {
+            name: &'static str,
+            insts: Vec<Inst>,
+            expected: Vec<u8>,
+        }
+
+        let tests = vec![
+            Test {
+                name: "push and pop",
+                insts: vec![
+                    Inst::Push(Operand::Reg(Reg::Rbp)),
+                    Inst::Push(Operand::Imm(42)),
+                    Inst::Pop(Reg::Rdi),
+                    Inst::Pop(Reg::R9),
+                ],
+                expected: vec![0x55, 0x68, 42, 0, 0, 0, 0x5f, 0x41, 0x59],
+            },
+            Test {
+                name: "prologue",
+                insts: vec![
+                    Inst::Mov(Operand::Reg(Reg::Rbp), Operand::Reg(Reg::Rsp)),
+                    Inst::Sub(Reg::Rsp, Operand::Imm(208)),
+                ],
+                expected: vec![0x48, 0x89, 0xe5, 0x48, 0x81, 0xec, 208, 0, 0, 0],
+            },
+            Test {
+                name: "memory",
+                insts: vec![
+                    Inst::Mov(Operand::Reg(Reg::Rax), Operand::Mem { base: Reg::Rax, disp: 0 }),
+                    Inst::Mov(Operand::Mem { base: Reg::Rax, disp: 0 }, Operand::Reg(Reg::Rdi)),
+                    Inst::Mov(Operand::Mem { base: Reg::Rbp, disp: -8 }, Operand::Reg(Reg::R8)),
+                    Inst::Mov(Operand::Reg(Reg::Rax), Operand::Mem { base: Reg::Rsp, disp: 0 }),
+                ],
+                expected: vec![
+                    0x48, 0x8b, 0x00, 0x48, 0x89, 0x38, 0x4c, 0x89, 0x45, 0xf8, 0x48, 0x8b, 0x04,
+                    0x24,
+                ],
+            },
+            Test {
+                name: "arithmetic",
+                insts: vec![
+                    Inst::Add(Reg::Rax, Operand::Reg(Reg::Rdi)),
+                    Inst::Imul(Reg::Rax, Reg::Rdi),
+                    Inst::Cqo,
+                    Inst::Idiv(Reg::Rdi),
+                ],
+                expected: vec![
+                    0x48, 0x01, 0xf8, 0x48, 0x0f, 0xaf, 0xc7, 0x48, 0x99, 0x48, 0xf7, 0xff,
+                ],
+            },
+            Test {
+                name: "compare",
+                insts: vec![
+                    Inst::Cmp(Reg::Rax, Operand::Reg(Reg::Rdi)),
+                    Inst::Set(Cond::Le),
+                    Inst::Movzb(Reg::Rax),
+                    Inst::Cmp(Reg::Rax, Operand::Imm(0)),
+                ],
+                expected: vec![
+                    0x48, 0x39, 0xf8, 0x0f, 0x9e, 0xc0, 0x48, 0x0f, 0xb6, 0xc0, 0x48, 0x81, 0xf8,
+                    0, 0, 0, 0,
+                ],
+            },
+            Test {
+                name: "jump",
+                insts: vec![
+                    Inst::Label(".L0".to_string()),
+                    Inst::Je(".L1".to_string()),
+                    Inst::Jmp(".L0".to_string()),
+                    Inst::Label(".L1".to_string()),
+                    Inst::Ret,
+                ],
+                expected: vec![0x0f, 0x84, 5, 0, 0, 0, 0xe9, 0xf5, 0xff, 0xff, 0xff, 0xc3],
+            },
+        ];
+
+        for t in tests {
+            let assembled = encode(&t.insts).unwrap();
+            assert_eq!(assembled.code, t.expected, "Failed in the {}", t.name);
+        }
+    }
+
+    #[test]
+    fn test_encode_call() {
+        let assembled = encode(&[Inst::Call("main".to_string())]).unwrap();
+        assert_eq!(assembled.code, vec![0xe8, 0, 0, 0, 0]);
+        assert_eq!(
+            assembled.relocations,
+            vec![Relocation {
+                offset: 1,
+                symbol: "main".to_string(),
+                kind: RelocationKind::Plt32,
+                addend: -4,
+            }]
+        );
+    }
+}
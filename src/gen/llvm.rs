@@ -0,0 +1,307 @@
+use std::collections::BTreeSet;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+use crate::ast::node::{LocalVar, Node, Nodes};
+use crate::gen::CodeGen;
+
+// LLVM IRのテキスト (.ll) を出力する
+// 変数は1つずつallocaし、値はすべてi64として扱う
+// allocaは関数の先頭に並べたいので、本体を組み立ててからまとめて書き出す
+pub struct LlvmCodeGen<W: Write> {
+    w: W,
+    body: String,
+    vars: BTreeSet<usize>, // 使われた変数のオフセット
+    value_counter: usize,
+    label_index_counter: usize,
+}
+
+impl<W: Write> CodeGen<W> for LlvmCodeGen<W> {
+    fn prefix(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn prologue(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn gen_from_nodes(&mut self, nodes: Nodes) -> io::Result<()> {
+        for node in nodes.0 {
+            self.gen_stmt(*node)?;
+        }
+        Ok(())
+    }
+
+    fn epilogue(&mut self) -> io::Result<()> {
+        // returnが無ければ最後に評価した式文の値を返す
+        let value = self.value();
+        self.inst(format_args!("{} = load i64, i64* %ret", value));
+        let ret = self.value();
+        self.inst(format_args!("{} = trunc i64 {} to i32", ret, value));
+        self.inst(format_args!("ret i32 {}", ret));
+
+        writeln!(self.w, "define i32 @main() {{")?;
+        writeln!(self.w, "entry:")?;
+        writeln!(self.w, "  %ret = alloca i64")?;
+        for offset in &self.vars {
+            writeln!(self.w, "  %var{} = alloca i64", offset)?;
+        }
+        writeln!(self.w, "  store i64 0, i64* %ret")?;
+        write!(self.w, "{}", self.body)?;
+        writeln!(self.w, "}}")?;
+        Ok(())
+    }
+}
+
+impl<W: Write> LlvmCodeGen<W> {
+    pub fn new(w: W) -> Self {
+        Self {
+            w,
+            body: String::new(),
+            vars: BTreeSet::new(),
+            value_counter: 0,
+            label_index_counter: 0,
+        }
+    }
+
+    fn inst(&mut self, inst: std::fmt::Arguments) {
+        writeln!(self.body, "  {}", inst).unwrap();
+    }
+
+    fn label(&mut self, label: &str) {
+        writeln!(self.body, "{}:", label).unwrap();
+    }
+
+    // 新しい仮想レジスタ
+    fn value(&mut self) -> String {
+        let value = format!("%t{}", self.value_counter);
+        self.value_counter += 1;
+        value
+    }
+
+    fn label_index(&mut self) -> usize {
+        let index = self.label_index_counter;
+        self.label_index_counter += 1;
+        index
+    }
+
+    fn lval(&mut self, node: Node) -> String {
+        if let Node::Lvar(LocalVar { ident: _, offset }) = node {
+            self.vars.insert(offset);
+            format!("%var{}", offset)
+        } else {
+            panic!("代入の左辺値が変数ではありません");
+        }
+    }
+
+    fn binary(&mut self, l: Node, r: Node, op: &str) -> io::Result<String> {
+        let l = self.gen_expr(l)?;
+        let r = self.gen_expr(r)?;
+        let value = self.value();
+        self.inst(format_args!("{} = {} i64 {}, {}", value, op, l, r));
+        Ok(value)
+    }
+
+    // icmpの結果はi1なのでi64に広げる
+    fn compare(&mut self, l: Node, r: Node, cond: &str) -> io::Result<String> {
+        let flag = self.binary(l, r, &format!("icmp {}", cond))?;
+        let value = self.value();
+        self.inst(format_args!("{} = zext i1 {} to i64", value, flag));
+        Ok(value)
+    }
+
+    // 条件式が0でなければ then に、そうでなければ els に分岐する
+    fn branch(&mut self, cond: Node, then: &str, els: &str) -> io::Result<()> {
+        let cond = self.gen_expr(cond)?;
+        let flag = self.value();
+        self.inst(format_args!("{} = icmp ne i64 {}, 0", flag, cond));
+        self.inst(format_args!("br i1 {}, label %{}, label %{}", flag, then, els));
+        Ok(())
+    }
+
+    fn gen_stmt(&mut self, node: Node) -> io::Result<()> {
+        match node {
+            Node::Return { expr } => {
+                let value = self.gen_expr(*expr)?;
+                let ret = self.value();
+                self.inst(format_args!("{} = trunc i64 {} to i32", ret, value));
+                self.inst(format_args!("ret i32 {}", ret));
+                // retの後ろに続く命令は到達しないブロックに置く
+                let label = format!("dead.{}", self.label_index());
+                self.label(&label);
+            }
+            Node::If { cond, then, els } => {
+                let index = self.label_index();
+                let (then_label, else_label, end_label) = (
+                    format!("then.{}", index),
+                    format!("else.{}", index),
+                    format!("end.{}", index),
+                );
+                if let Some(els) = els {
+                    self.branch(*cond, &then_label, &else_label)?;
+                    self.label(&then_label);
+                    self.gen_stmt(*then)?;
+                    self.inst(format_args!("br label %{}", end_label));
+                    self.label(&else_label);
+                    self.gen_stmt(*els)?;
+                } else {
+                    self.branch(*cond, &then_label, &end_label)?;
+                    self.label(&then_label);
+                    self.gen_stmt(*then)?;
+                }
+                self.inst(format_args!("br label %{}", end_label));
+                self.label(&end_label);
+            }
+            Node::While { cond, then } => {
+                let index = self.label_index();
+                let (begin_label, body_label, end_label) = (
+                    format!("begin.{}", index),
+                    format!("body.{}", index),
+                    format!("end.{}", index),
+                );
+                self.inst(format_args!("br label %{}", begin_label));
+                self.label(&begin_label);
+                self.branch(*cond, &body_label, &end_label)?;
+                self.label(&body_label);
+                self.gen_stmt(*then)?;
+                self.inst(format_args!("br label %{}", begin_label));
+                self.label(&end_label);
+            }
+            Node::For {
+                init,
+                cond,
+                step,
+                then,
+            } => {
+                // 初期化式と更新式は式文ではないので、値を %ret に書かない
+                if let Some(init) = init {
+                    self.gen_expr(*init)?;
+                }
+                let index = self.label_index();
+                let (begin_label, body_label, end_label) = (
+                    format!("begin.{}", index),
+                    format!("body.{}", index),
+                    format!("end.{}", index),
+                );
+                self.inst(format_args!("br label %{}", begin_label));
+                self.label(&begin_label);
+                if let Some(cond) = cond {
+                    self.branch(*cond, &body_label, &end_label)?;
+                } else {
+                    self.inst(format_args!("br label %{}", body_label));
+                }
+                self.label(&body_label);
+                self.gen_stmt(*then)?;
+                if let Some(step) = step {
+                    self.gen_expr(*step)?;
+                }
+                self.inst(format_args!("br label %{}", begin_label));
+                self.label(&end_label);
+            }
+            Node::Block { stmts } => {
+                for stmt in stmts {
+                    self.gen_stmt(*stmt)?;
+                }
+            }
+            Node::Asm { .. } => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "inline asm is not supported in LLVM IR output",
+                ))
+            }
+            // 式文の値はreturnが無いときの戻り値になる
+            expr => {
+                let value = self.gen_expr(expr)?;
+                self.inst(format_args!("store i64 {}, i64* %ret", value));
+            }
+        }
+        Ok(())
+    }
+
+    // 式の値 (定数か仮想レジスタ) を返す
+    fn gen_expr(&mut self, node: Node) -> io::Result<String> {
+        match node {
+            Node::Num(n) => Ok(n.to_string()),
+            Node::Add { l, r } => self.binary(*l, *r, "add"),
+            Node::Sub { l, r } => self.binary(*l, *r, "sub"),
+            Node::Mul { l, r } => self.binary(*l, *r, "mul"),
+            Node::Div { l, r } => self.binary(*l, *r, "sdiv"),
+            Node::Eq { l, r } => self.compare(*l, *r, "eq"),
+            Node::Ne { l, r } => self.compare(*l, *r, "ne"),
+            Node::Lt { l, r } => self.compare(*l, *r, "slt"),
+            Node::Le { l, r } => self.compare(*l, *r, "sle"),
+            Node::Lvar(_) => {
+                let var = self.lval(node);
+                let value = self.value();
+                self.inst(format_args!("{} = load i64, i64* {}", value, var));
+                Ok(value)
+            }
+            Node::Assign { l, r } => {
+                let var = self.lval(*l);
+                let value = self.gen_expr(*r)?;
+                self.inst(format_args!("store i64 {}, i64* {}", value, var));
+                Ok(value)
+            }
+            stmt => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("statement used as an expression: {}", stmt),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::parser::Parser;
+    use crate::lexer::tokenize;
+
+    use super::*;
+
+    #[test]
+    fn test_gen() {
+        let tokens =
+            tokenize(&mut "a = 3; while (a) a = a - 1; return a == 0;".chars().peekable()).unwrap();
+        let nodes = Parser::new(tokens.iter()).parse().unwrap();
+
+        let mut out = Vec::new();
+        let mut gen = LlvmCodeGen::new(&mut out);
+        gen.gen_from_nodes(nodes).unwrap();
+        gen.epilogue().unwrap();
+
+        let expected = [
+            "define i32 @main() {",
+            "entry:",
+            "  %ret = alloca i64",
+            "  %var8 = alloca i64",
+            "  store i64 0, i64* %ret",
+            // a = 3;
+            "  store i64 3, i64* %var8",
+            "  store i64 3, i64* %ret",
+            // while (a) a = a - 1;
+            "  br label %begin.0",
+            "begin.0:",
+            "  %t0 = load i64, i64* %var8",
+            "  %t1 = icmp ne i64 %t0, 0",
+            "  br i1 %t1, label %body.0, label %end.0",
+            "body.0:",
+            "  %t2 = load i64, i64* %var8",
+            "  %t3 = sub i64 %t2, 1",
+            "  store i64 %t3, i64* %var8",
+            "  store i64 %t3, i64* %ret",
+            "  br label %begin.0",
+            "end.0:",
+            // return a == 0;
+            "  %t4 = load i64, i64* %var8",
+            "  %t5 = icmp eq i64 %t4, 0",
+            "  %t6 = zext i1 %t5 to i64",
+            "  %t7 = trunc i64 %t6 to i32",
+            "  ret i32 %t7",
+            "dead.1:",
+            "  %t8 = load i64, i64* %ret",
+            "  %t9 = trunc i64 %t8 to i32",
+            "  ret i32 %t9",
+            "}",
+        ];
+        assert_eq!(String::from_utf8(out).unwrap(), expected.join("\n") + "\n");
+    }
+}
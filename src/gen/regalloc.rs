@@ -0,0 +1,292 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::gen::x86_64::Reg;
+use crate::ir::{Function, Inst, VReg};
+
+// 仮想レジスタの置き場所
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Location {
+    Reg(Reg),
+    Stack(usize), // スピル用の領域の何番目か
+}
+
+#[derive(Debug, PartialEq, Default)]
+pub struct Allocation {
+    pub locations: Vec<Location>, // 仮想レジスタの番号で引く
+    pub stack_slots: usize,
+    pub regs: Vec<Reg>, // 割り当てに使ったレジスタ
+}
+
+impl Allocation {
+    // 割り当てをせず、仮想レジスタ %n をすべてスタックの n 番目に置く
+    pub fn spill_all(func: &Function) -> Self {
+        Self {
+            locations: (0..func.vregs).map(Location::Stack).collect(),
+            stack_slots: func.vregs,
+            regs: Vec::new(),
+        }
+    }
+
+    pub fn location(&self, v: VReg) -> Location {
+        self.locations[v.0]
+    }
+}
+
+// 生存区間 [start, end]
+// 位置はブロックを並べた順に命令と終端命令へ振った番号
+#[derive(Debug)]
+struct Interval {
+    vreg: VReg,
+    start: usize,
+    end: usize,
+    forbidden: HashSet<Reg>, // 区間の途中で壊されるレジスタ
+}
+
+// linear scanでレジスタを割り当てる
+// regs は先に書いたものから使い、clobbers は命令が壊すレジスタを返す
+// 足りなくなったら最も遠くまで生きている区間をスタックに追い出す
+pub fn linear_scan(
+    func: &Function,
+    regs: &[Reg],
+    clobbers: impl Fn(&Inst) -> Vec<Reg>,
+) -> Allocation {
+    let mut intervals = live_intervals(func, clobbers);
+    intervals.sort_by_key(|i| (i.start, i.vreg.0));
+
+    let mut locations = vec![None; func.vregs];
+    let mut stack_slots = 0;
+    let mut spill = |locations: &mut Vec<Option<Location>>, v: VReg| {
+        locations[v.0] = Some(Location::Stack(stack_slots));
+        stack_slots += 1;
+    };
+
+    // 今レジスタに載っている区間 (終わり, 仮想レジスタ, レジスタ)
+    let mut active: Vec<(usize, VReg, Reg)> = Vec::new();
+    for interval in &intervals {
+        // 命令はオペランドを読んでから結果を書くので、ここで終わる区間のレジスタは使ってよい
+        active.retain(|(end, _, _)| *end > interval.start);
+
+        let free = regs.iter().find(|r| {
+            !interval.forbidden.contains(r) && !active.iter().any(|(_, _, used)| used == *r)
+        });
+        if let Some(&reg) = free {
+            locations[interval.vreg.0] = Some(Location::Reg(reg));
+            active.push((interval.end, interval.vreg, reg));
+            continue;
+        }
+
+        let victim = active
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, _, reg))| !interval.forbidden.contains(reg))
+            .max_by_key(|(_, (end, _, _))| *end)
+            .map(|(i, &a)| (i, a));
+        match victim {
+            Some((i, (end, vreg, reg))) if end > interval.end => {
+                spill(&mut locations, vreg);
+                locations[interval.vreg.0] = Some(Location::Reg(reg));
+                active[i] = (interval.end, interval.vreg, reg);
+            }
+            _ => spill(&mut locations, interval.vreg),
+        }
+    }
+
+    let locations: Vec<Location> = locations
+        .into_iter()
+        .map(|l| l.expect("生存区間の無い仮想レジスタがあります"))
+        .collect();
+    let used: HashSet<Reg> = locations
+        .iter()
+        .filter_map(|l| match l {
+            Location::Reg(r) => Some(*r),
+            Location::Stack(_) => None,
+        })
+        .collect();
+    Allocation {
+        locations,
+        stack_slots,
+        regs: regs.iter().copied().filter(|r| used.contains(r)).collect(),
+    }
+}
+
+// ブロック単位で生存解析をして、仮想レジスタごとに生きている範囲をまとめる
+// 範囲は1つの区間で近似するので、途中に穴があっても埋める
+fn live_intervals(func: &Function, clobbers: impl Fn(&Inst) -> Vec<Reg>) -> Vec<Interval> {
+    let index: HashMap<_, _> = func
+        .blocks
+        .iter()
+        .enumerate()
+        .map(|(i, b)| (b.id, i))
+        .collect();
+
+    // ブロック内で定義より先に使う仮想レジスタと、定義する仮想レジスタ
+    let mut uses = vec![HashSet::new(); func.blocks.len()];
+    let mut defs = vec![HashSet::new(); func.blocks.len()];
+    for (i, block) in func.blocks.iter().enumerate() {
+        let used = block.insts.iter().map(|inst| (inst.uses(), inst.defs()));
+        let term = std::iter::once((block.term.uses(), vec![]));
+        for (u, d) in used.chain(term) {
+            uses[i].extend(u.into_iter().filter(|v| !defs[i].contains(v)));
+            defs[i].extend(d);
+        }
+    }
+
+    let mut live_in = vec![HashSet::<VReg>::new(); func.blocks.len()];
+    let mut live_out = vec![HashSet::<VReg>::new(); func.blocks.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (i, block) in func.blocks.iter().enumerate().rev() {
+            let out: HashSet<VReg> = block
+                .term
+                .successors()
+                .iter()
+                .flat_map(|s| live_in[index[s]].iter().copied())
+                .collect();
+            let mut inn = uses[i].clone();
+            inn.extend(out.difference(&defs[i]).copied());
+            if out != live_out[i] || inn != live_in[i] {
+                changed = true;
+                live_out[i] = out;
+                live_in[i] = inn;
+            }
+        }
+    }
+
+    let mut ranges: HashMap<VReg, (usize, usize)> = HashMap::new();
+    let mut extend = |v: VReg, pos: usize| {
+        let range = ranges.entry(v).or_insert((pos, pos));
+        range.0 = range.0.min(pos);
+        range.1 = range.1.max(pos);
+    };
+    let mut clobbered = Vec::new();
+    let mut pos = 0;
+    for (i, block) in func.blocks.iter().enumerate() {
+        let first = pos;
+        for v in &live_in[i] {
+            extend(*v, first);
+        }
+        for inst in &block.insts {
+            for v in inst.uses().into_iter().chain(inst.defs()) {
+                extend(v, pos);
+            }
+            let regs = clobbers(inst);
+            if !regs.is_empty() {
+                clobbered.push((pos, regs));
+            }
+            pos += 1;
+        }
+        for v in block.term.uses() {
+            extend(v, pos);
+        }
+        for v in &live_out[i] {
+            extend(*v, pos);
+        }
+        pos += 1;
+    }
+
+    ranges
+        .into_iter()
+        .map(|(vreg, (start, end))| Interval {
+            vreg,
+            start,
+            end,
+            forbidden: clobbered
+                .iter()
+                .filter(|(pos, _)| start <= *pos && *pos <= end)
+                .flat_map(|(_, regs)| regs.iter().copied())
+                .collect(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::parser::Parser;
+    use crate::ir::lower;
+    use crate::lexer::tokenize;
+
+    use super::*;
+
+    #[test]
+    fn test_linear_scan() {
+        struct Test {
+            name: &'static str,
+            input: &'static str,
+            regs: Vec<Reg>,
+            expected: Vec<Location>,
+        }
+
+        let tests = vec![
+            Test {
+                name: "reuse registers",
+                input: "1 + 2; 3;",
+                regs: vec![Reg::Rbx, Reg::R12],
+                expected: vec![
+                    Location::Reg(Reg::Rbx),
+                    Location::Reg(Reg::R12),
+                    Location::Reg(Reg::Rbx),
+                    Location::Reg(Reg::Rbx),
+                    Location::Reg(Reg::Rbx),
+                ],
+            },
+            Test {
+                name: "spill the longest interval",
+                input: "1 + (2 + 3);",
+                regs: vec![Reg::Rbx, Reg::R12],
+                expected: vec![
+                    Location::Stack(0),
+                    Location::Reg(Reg::R12),
+                    Location::Reg(Reg::Rbx),
+                    Location::Reg(Reg::Rbx),
+                    Location::Reg(Reg::Rbx),
+                    Location::Reg(Reg::Rbx),
+                ],
+            },
+            Test {
+                name: "loop",
+                input: "5; for (a = 0; a < 3; a = a + 1) {}",
+                regs: vec![Reg::Rbx, Reg::R12, Reg::R13],
+                expected: vec![
+                    Location::Reg(Reg::Rbx),
+                    Location::Reg(Reg::Rbx),
+                    Location::Reg(Reg::Rbx),
+                    Location::Reg(Reg::R12),
+                    Location::Reg(Reg::Rbx),
+                    Location::Reg(Reg::Rbx),
+                    Location::Reg(Reg::R12),
+                    Location::Reg(Reg::Rbx),
+                    Location::Reg(Reg::Rbx),
+                ],
+            },
+            Test {
+                name: "avoid clobbered registers",
+                input: "a = 4; 1 + a / 2;",
+                regs: vec![Reg::Rdx, Reg::Rbx],
+                expected: vec![
+                    Location::Reg(Reg::Rdx),
+                    Location::Stack(0),
+                    Location::Reg(Reg::Rbx),
+                    Location::Stack(1),
+                    Location::Reg(Reg::Rbx),
+                    Location::Reg(Reg::Rdx),
+                    Location::Reg(Reg::Rdx),
+                ],
+            },
+        ];
+
+        for t in tests {
+            let tokens = tokenize(&mut t.input.chars().peekable()).unwrap();
+            let nodes = Parser::new(tokens.iter()).parse().unwrap();
+            let clobbers = |inst: &Inst| match inst {
+                Inst::Bin {
+                    op: crate::ir::BinOp::Div,
+                    ..
+                } => vec![Reg::Rdx],
+                _ => vec![],
+            };
+            let alloc = linear_scan(&lower(&nodes), &t.regs, clobbers);
+            assert_eq!(alloc.locations, t.expected, "Failed in the {}", t.name);
+        }
+    }
+}
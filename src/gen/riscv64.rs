@@ -0,0 +1,380 @@
+use std::io::{self, Write};
+
+use crate::ast::node::{AsmOperand, LocalVar, Node, Nodes};
+use crate::gen::{asm_text, assign_asm_operands, AsmOperands, AsmPlace, CodeGen};
+
+// 拡張asmの "r" オペランドに割り当てるレジスタ (psABIの一時レジスタ)
+const ASM_REGISTERS: [&str; 7] = ["t0", "t1", "t2", "t3", "t4", "t5", "t6"];
+
+// 変数 (s0-8 から s0-208) と、returnが無いときの戻り値 (s0-216) の領域
+const RET_OFFSET: usize = 216;
+const FRAME_SIZE: usize = 224; // FIXME: 208 / 8 = 26個しか変数宣言できない
+
+// RV64GC (RISC-V psABI) 向けのアセンブリを出力する
+// x86-64と同じスタックマシンで、1要素8バイトで積む
+// 式は値を1つ積み、文は何も積まない
+pub struct Riscv64CodeGen<W: Write> {
+    w: W,
+    label_index_counter: usize,
+}
+
+impl<W: Write> CodeGen<W> for Riscv64CodeGen<W> {
+    fn prefix(&mut self) -> io::Result<()> {
+        writeln!(self.w, ".text")?;
+        writeln!(self.w, ".globl main")?;
+        writeln!(self.w, "main:")?;
+        Ok(())
+    }
+
+    fn prologue(&mut self) -> io::Result<()> {
+        writeln!(self.w, "  addi sp, sp, -16")?;
+        writeln!(self.w, "  sd ra, 8(sp)")?;
+        writeln!(self.w, "  sd s0, 0(sp)")?;
+        writeln!(self.w, "  mv s0, sp")?;
+        // 変数の領域の後ろにreturnが無いときの戻り値を置く
+        writeln!(self.w, "  addi sp, sp, -{}", FRAME_SIZE)?;
+        writeln!(self.w, "  sd zero, -{}(s0)", RET_OFFSET)?;
+        Ok(())
+    }
+
+    fn gen_from_nodes(&mut self, nodes: Nodes) -> io::Result<()> {
+        for node in nodes.0 {
+            self.gen_stmt(*node)?;
+        }
+        Ok(())
+    }
+
+    fn epilogue(&mut self) -> io::Result<()> {
+        writeln!(self.w, "  ld a0, -{}(s0)", RET_OFFSET)?;
+        self.ret()
+    }
+}
+
+impl<W: Write> Riscv64CodeGen<W> {
+    pub fn new(w: W) -> Self {
+        Self {
+            w,
+            label_index_counter: 0,
+        }
+    }
+
+    fn push(&mut self, reg: &str) -> io::Result<()> {
+        writeln!(self.w, "  addi sp, sp, -8")?;
+        writeln!(self.w, "  sd {}, 0(sp)", reg)
+    }
+
+    fn pop(&mut self, reg: &str) -> io::Result<()> {
+        writeln!(self.w, "  ld {}, 0(sp)", reg)?;
+        writeln!(self.w, "  addi sp, sp, 8")
+    }
+
+    // a0 を戻り値にして main から戻る
+    fn ret(&mut self) -> io::Result<()> {
+        writeln!(self.w, "  mv sp, s0")?;
+        writeln!(self.w, "  ld s0, 0(sp)")?;
+        writeln!(self.w, "  ld ra, 8(sp)")?;
+        writeln!(self.w, "  addi sp, sp, 16")?;
+        writeln!(self.w, "  ret")
+    }
+
+    // 式の値を捨てる
+    fn gen_discard(&mut self, node: Node) -> io::Result<()> {
+        self.gen_node(node)?;
+        self.pop("a0")
+    }
+
+    fn lval(&mut self, node: Node) -> io::Result<()> {
+        if let Node::Lvar(LocalVar { ident: _, offset }) = node {
+            writeln!(self.w, "  addi a0, s0, -{}", offset)?;
+            self.push("a0")
+        } else {
+            panic!("代入の左辺値が変数ではありません");
+        }
+    }
+
+    fn label_index(&mut self) -> String {
+        let label = format!(".L{}", self.label_index_counter);
+        self.label_index_counter += 1;
+        label
+    }
+
+    // 2つのオペランドを a0 と a1 に取り出す
+    fn binary(&mut self, l: Node, r: Node) -> io::Result<()> {
+        self.gen_node(l)?;
+        self.gen_node(r)?;
+        self.pop("a1")?;
+        self.pop("a0")
+    }
+
+    // 比較結果の0か1を a0 に入れる
+    fn compare(&mut self, l: Node, r: Node, insts: &[&str]) -> io::Result<()> {
+        self.binary(l, r)?;
+        for inst in insts {
+            writeln!(self.w, "  {}", inst)?;
+        }
+        self.push("a0")
+    }
+
+    fn gen_stmt(&mut self, node: Node) -> io::Result<()> {
+        match node {
+            Node::Return { expr } => {
+                self.gen_node(*expr)?;
+                self.pop("a0")?;
+                self.ret()
+            }
+            Node::If { cond, then, els } => {
+                let label_index = self.label_index();
+                self.gen_node(*cond)?;
+                self.pop("a0")?;
+                if let Some(els) = els {
+                    writeln!(self.w, "  beqz a0, .Lelse{}", label_index)?;
+                    self.gen_stmt(*then)?;
+                    writeln!(self.w, "  j .Lend{}", label_index)?;
+                    writeln!(self.w, ".Lelse{}:", label_index)?;
+                    self.gen_stmt(*els)?;
+                } else {
+                    writeln!(self.w, "  beqz a0, .Lend{}", label_index)?;
+                    self.gen_stmt(*then)?;
+                }
+                writeln!(self.w, ".Lend{}:", label_index)
+            }
+            Node::While { cond, then } => {
+                let label_index = self.label_index();
+                writeln!(self.w, ".Lbegin{}:", label_index)?;
+                self.gen_node(*cond)?;
+                self.pop("a0")?;
+                writeln!(self.w, "  beqz a0, .Lend{}", label_index)?;
+                self.gen_stmt(*then)?;
+                writeln!(self.w, "  j .Lbegin{}", label_index)?;
+                writeln!(self.w, ".Lend{}:", label_index)
+            }
+            Node::For {
+                init,
+                cond,
+                step,
+                then,
+            } => {
+                // 初期化式と更新式は式文ではないので、値を捨てて戻り値を変えない
+                if let Some(init) = init {
+                    self.gen_discard(*init)?;
+                }
+                let label_index = self.label_index();
+                writeln!(self.w, ".Lbegin{}:", label_index)?;
+                // 条件式が無ければ無限ループ
+                if let Some(cond) = cond {
+                    self.gen_node(*cond)?;
+                    self.pop("a0")?;
+                    writeln!(self.w, "  beqz a0, .Lend{}", label_index)?;
+                }
+                self.gen_stmt(*then)?;
+                if let Some(step) = step {
+                    self.gen_discard(*step)?;
+                }
+                writeln!(self.w, "  j .Lbegin{}", label_index)?;
+                writeln!(self.w, ".Lend{}:", label_index)
+            }
+            Node::Block { stmts } => {
+                for stmt in stmts {
+                    self.gen_stmt(*stmt)?;
+                }
+                Ok(())
+            }
+            Node::Asm {
+                template,
+                extended,
+                outputs,
+                inputs,
+                clobbers,
+            } => self.asm(&template, extended, outputs, inputs, &clobbers),
+            // 式文の値はreturnが無いときの戻り値になる
+            expr => {
+                self.gen_node(expr)?;
+                self.pop("a0")?;
+                writeln!(self.w, "  sd a0, -{}(s0)", RET_OFFSET)
+            }
+        }
+    }
+
+    fn gen_node(&mut self, node: Node) -> io::Result<()> {
+        match node {
+            Node::Num(n) => {
+                writeln!(self.w, "  li a0, {}", n)?;
+                self.push("a0")
+            }
+            Node::Add { l, r } => {
+                self.binary(*l, *r)?;
+                writeln!(self.w, "  add a0, a0, a1")?;
+                self.push("a0")
+            }
+            Node::Sub { l, r } => {
+                self.binary(*l, *r)?;
+                writeln!(self.w, "  sub a0, a0, a1")?;
+                self.push("a0")
+            }
+            Node::Mul { l, r } => {
+                self.binary(*l, *r)?;
+                writeln!(self.w, "  mul a0, a0, a1")?;
+                self.push("a0")
+            }
+            Node::Div { l, r } => {
+                self.binary(*l, *r)?;
+                writeln!(self.w, "  div a0, a0, a1")?;
+                self.push("a0")
+            }
+            Node::Eq { l, r } => self.compare(*l, *r, &["sub a0, a0, a1", "seqz a0, a0"]),
+            Node::Ne { l, r } => self.compare(*l, *r, &["sub a0, a0, a1", "snez a0, a0"]),
+            Node::Lt { l, r } => self.compare(*l, *r, &["slt a0, a0, a1"]),
+            // a0 <= a1 は !(a1 < a0)
+            Node::Le { l, r } => self.compare(*l, *r, &["slt a0, a1, a0", "xori a0, a0, 1"]),
+            Node::Lvar(_) => {
+                self.lval(node)?;
+                self.pop("a0")?;
+                writeln!(self.w, "  ld a0, 0(a0)")?;
+                self.push("a0")
+            }
+            Node::Assign { l, r } => {
+                self.lval(*l)?;
+                self.gen_node(*r)?;
+                self.pop("a1")?;
+                self.pop("a0")?;
+                writeln!(self.w, "  sd a1, 0(a0)")?;
+                self.push("a1")
+            }
+            stmt => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("statement used as an expression: {}", stmt),
+            )),
+        }
+    }
+
+    fn asm(
+        &mut self,
+        template: &str,
+        extended: bool,
+        outputs: Vec<AsmOperand>,
+        inputs: Vec<AsmOperand>,
+        clobbers: &[String],
+    ) -> io::Result<()> {
+        let AsmOperands {
+            places,
+            loads,
+            stores,
+        } = assign_asm_operands(&ASM_REGISTERS, |r| r, outputs, inputs, clobbers)?;
+
+        // レジスタに載せる値を積んでから、逆順に取り出す
+        let mut regs = Vec::new();
+        for (reg, expr) in loads {
+            self.gen_node(*expr)?;
+            regs.push(reg);
+        }
+        for reg in regs.into_iter().rev() {
+            self.pop(reg)?;
+        }
+
+        let operands: Vec<String> = places
+            .into_iter()
+            .map(|place| match place {
+                AsmPlace::Reg(reg) => reg.to_string(),
+                AsmPlace::Mem(offset) => format!("-{}(s0)", offset),
+                AsmPlace::Imm(n) => format!("{}", n),
+            })
+            .collect();
+        writeln!(self.w, "{}", asm_text(template, extended, &operands)?)?;
+
+        // 出力オペランドをレジスタから変数に書き戻す
+        for (reg, offset) in stores {
+            writeln!(self.w, "  sd {}, -{}(s0)", reg, offset)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::parser::Parser;
+    use crate::lexer::tokenize;
+
+    use super::*;
+
+    #[test]
+    fn test_gen() {
+        let tokens =
+            tokenize(&mut "a = 70000; if (a >= 1) return a / 2;".chars().peekable()).unwrap();
+        let nodes = Parser::new(tokens.iter()).parse().unwrap();
+
+        let mut out = Vec::new();
+        let mut gen = Riscv64CodeGen::new(&mut out);
+        gen.gen_from_nodes(nodes).unwrap();
+
+        let expected = [
+            // a = 70000;
+            "  addi a0, s0, -8",
+            "  addi sp, sp, -8",
+            "  sd a0, 0(sp)",
+            "  li a0, 70000",
+            "  addi sp, sp, -8",
+            "  sd a0, 0(sp)",
+            "  ld a1, 0(sp)",
+            "  addi sp, sp, 8",
+            "  ld a0, 0(sp)",
+            "  addi sp, sp, 8",
+            "  sd a1, 0(a0)",
+            "  addi sp, sp, -8",
+            "  sd a1, 0(sp)",
+            "  ld a0, 0(sp)",
+            "  addi sp, sp, 8",
+            "  sd a0, -216(s0)",
+            // if (1 <= a)
+            "  li a0, 1",
+            "  addi sp, sp, -8",
+            "  sd a0, 0(sp)",
+            "  addi a0, s0, -8",
+            "  addi sp, sp, -8",
+            "  sd a0, 0(sp)",
+            "  ld a0, 0(sp)",
+            "  addi sp, sp, 8",
+            "  ld a0, 0(a0)",
+            "  addi sp, sp, -8",
+            "  sd a0, 0(sp)",
+            "  ld a1, 0(sp)",
+            "  addi sp, sp, 8",
+            "  ld a0, 0(sp)",
+            "  addi sp, sp, 8",
+            "  slt a0, a1, a0",
+            "  xori a0, a0, 1",
+            "  addi sp, sp, -8",
+            "  sd a0, 0(sp)",
+            "  ld a0, 0(sp)",
+            "  addi sp, sp, 8",
+            "  beqz a0, .Lend.L0",
+            // return a / 2;
+            "  addi a0, s0, -8",
+            "  addi sp, sp, -8",
+            "  sd a0, 0(sp)",
+            "  ld a0, 0(sp)",
+            "  addi sp, sp, 8",
+            "  ld a0, 0(a0)",
+            "  addi sp, sp, -8",
+            "  sd a0, 0(sp)",
+            "  li a0, 2",
+            "  addi sp, sp, -8",
+            "  sd a0, 0(sp)",
+            "  ld a1, 0(sp)",
+            "  addi sp, sp, 8",
+            "  ld a0, 0(sp)",
+            "  addi sp, sp, 8",
+            "  div a0, a0, a1",
+            "  addi sp, sp, -8",
+            "  sd a0, 0(sp)",
+            "  ld a0, 0(sp)",
+            "  addi sp, sp, 8",
+            "  mv sp, s0",
+            "  ld s0, 0(sp)",
+            "  ld ra, 8(sp)",
+            "  addi sp, sp, 16",
+            "  ret",
+            ".Lend.L0:",
+        ];
+        assert_eq!(String::from_utf8(out).unwrap(), expected.join("\n") + "\n");
+    }
+}
@@ -0,0 +1,681 @@
+use std::fmt;
+use std::io::{self, Write};
+
+use crate::ast::node::{LocalVar, Node, Nodes};
+use crate::gen::CodeGen;
+
+// 線形メモリ1ページ (64KiB) の末尾からシャドウスタックを伸ばす
+const STACK_TOP: i32 = 65536;
+const FRAME_SIZE: i32 = 208; // FIXME: 208 / 8 = 26個しか変数宣言できない
+
+// 関数のローカル変数
+// Cの変数はアドレスを取れるようにシャドウスタックに置き、wasmのローカルは作業用にだけ使う
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Local {
+    Fp,  // i32: シャドウスタック上のフレームの先頭
+    Ret, // i64: 最後に評価した式文の値 (returnが無いときの戻り値)
+    Tmp, // i64: 代入の値を一時的に退避する
+}
+
+impl Local {
+    fn index(self) -> u32 {
+        self as u32
+    }
+
+    fn name(self) -> &'static str {
+        ["$fp", "$ret", "$tmp"][self as usize]
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Inst {
+    I32Const(i32),
+    I64Const(i64),
+    I32Sub,
+    I64Add,
+    I64Sub,
+    I64Mul,
+    I64DivS,
+    I64Eqz,
+    I64Eq,
+    I64Ne,
+    I64LtS,
+    I64LeS,
+    I64ExtendI32U,
+    I32WrapI64,
+    I64Load,
+    I64Store,
+    LocalGet(Local),
+    LocalSet(Local),
+    LocalTee(Local),
+    GlobalGetSp,
+    GlobalSetSp,
+    Block,
+    Loop,
+    If,
+    Else,
+    End,
+    Br(u32), // 外側に何段目のブロックへ飛ぶか
+    BrIf(u32),
+    Return,
+    Drop,
+}
+
+impl fmt::Display for Inst {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Inst::I32Const(n) => write!(f, "i32.const {}", n),
+            Inst::I64Const(n) => write!(f, "i64.const {}", n),
+            Inst::I32Sub => write!(f, "i32.sub"),
+            Inst::I64Add => write!(f, "i64.add"),
+            Inst::I64Sub => write!(f, "i64.sub"),
+            Inst::I64Mul => write!(f, "i64.mul"),
+            Inst::I64DivS => write!(f, "i64.div_s"),
+            Inst::I64Eqz => write!(f, "i64.eqz"),
+            Inst::I64Eq => write!(f, "i64.eq"),
+            Inst::I64Ne => write!(f, "i64.ne"),
+            Inst::I64LtS => write!(f, "i64.lt_s"),
+            Inst::I64LeS => write!(f, "i64.le_s"),
+            Inst::I64ExtendI32U => write!(f, "i64.extend_i32_u"),
+            Inst::I32WrapI64 => write!(f, "i32.wrap_i64"),
+            Inst::I64Load => write!(f, "i64.load"),
+            Inst::I64Store => write!(f, "i64.store"),
+            Inst::LocalGet(l) => write!(f, "local.get {}", l.name()),
+            Inst::LocalSet(l) => write!(f, "local.set {}", l.name()),
+            Inst::LocalTee(l) => write!(f, "local.tee {}", l.name()),
+            Inst::GlobalGetSp => write!(f, "global.get $sp"),
+            Inst::GlobalSetSp => write!(f, "global.set $sp"),
+            Inst::Block => write!(f, "block"),
+            Inst::Loop => write!(f, "loop"),
+            Inst::If => write!(f, "if"),
+            Inst::Else => write!(f, "else"),
+            Inst::End => write!(f, "end"),
+            Inst::Br(depth) => write!(f, "br {}", depth),
+            Inst::BrIf(depth) => write!(f, "br_if {}", depth),
+            Inst::Return => write!(f, "return"),
+            Inst::Drop => write!(f, "drop"),
+        }
+    }
+}
+
+// ASTからwasmの命令列を作る
+// 式の値はwasmのオペランドスタックに積み、文は値を残さない
+#[derive(Default)]
+pub struct Selector {
+    insts: Vec<Inst>,
+}
+
+impl Selector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn take(&mut self) -> Vec<Inst> {
+        std::mem::take(&mut self.insts)
+    }
+
+    pub fn prologue(&mut self) {
+        self.emit(Inst::GlobalGetSp);
+        self.emit(Inst::LocalTee(Local::Fp));
+        self.emit(Inst::I32Const(FRAME_SIZE));
+        self.emit(Inst::I32Sub);
+        self.emit(Inst::GlobalSetSp);
+    }
+
+    pub fn gen_from_nodes(&mut self, nodes: Nodes) -> io::Result<()> {
+        for node in nodes.0 {
+            self.gen_stmt(*node)?;
+        }
+        Ok(())
+    }
+
+    pub fn epilogue(&mut self) {
+        self.emit(Inst::LocalGet(Local::Fp));
+        self.emit(Inst::GlobalSetSp);
+        self.emit(Inst::LocalGet(Local::Ret));
+        self.emit(Inst::I32WrapI64);
+    }
+
+    fn emit(&mut self, inst: Inst) {
+        self.insts.push(inst);
+    }
+
+    // 変数のアドレス (i32) を積む
+    fn lval(&mut self, node: Node) {
+        if let Node::Lvar(LocalVar { ident: _, offset }) = node {
+            self.emit(Inst::LocalGet(Local::Fp));
+            self.emit(Inst::I32Const(offset as i32));
+            self.emit(Inst::I32Sub);
+        } else {
+            panic!("代入の左辺値が変数ではありません");
+        }
+    }
+
+    fn binary(&mut self, l: Node, r: Node, inst: Inst) -> io::Result<()> {
+        self.gen_expr(l)?;
+        self.gen_expr(r)?;
+        self.emit(inst);
+        Ok(())
+    }
+
+    // 比較結果はi32なのでi64に広げる
+    fn compare(&mut self, l: Node, r: Node, inst: Inst) -> io::Result<()> {
+        self.binary(l, r, inst)?;
+        self.emit(Inst::I64ExtendI32U);
+        Ok(())
+    }
+
+    // 条件式の値が0でなければ1 (i32) を積む
+    fn cond(&mut self, node: Node) -> io::Result<()> {
+        self.gen_expr(node)?;
+        self.emit(Inst::I64Const(0));
+        self.emit(Inst::I64Ne);
+        Ok(())
+    }
+
+    fn gen_stmt(&mut self, node: Node) -> io::Result<()> {
+        match node {
+            Node::Return { expr } => {
+                self.gen_expr(*expr)?;
+                self.emit(Inst::I32WrapI64);
+                self.emit(Inst::LocalGet(Local::Fp));
+                self.emit(Inst::GlobalSetSp);
+                self.emit(Inst::Return);
+            }
+            Node::If { cond, then, els } => {
+                self.cond(*cond)?;
+                self.emit(Inst::If);
+                self.gen_stmt(*then)?;
+                if let Some(els) = els {
+                    self.emit(Inst::Else);
+                    self.gen_stmt(*els)?;
+                }
+                self.emit(Inst::End);
+            }
+            Node::While { cond, then } => {
+                self.emit(Inst::Block);
+                self.emit(Inst::Loop);
+                self.gen_expr(*cond)?;
+                self.emit(Inst::I64Eqz);
+                self.emit(Inst::BrIf(1));
+                self.gen_stmt(*then)?;
+                self.emit(Inst::Br(0));
+                self.emit(Inst::End);
+                self.emit(Inst::End);
+            }
+            Node::For {
+                init,
+                cond,
+                step,
+                then,
+            } => {
+                // 初期化式と更新式は式文ではないので、値を捨てて $ret を変えない
+                if let Some(init) = init {
+                    self.gen_expr(*init)?;
+                    self.emit(Inst::Drop);
+                }
+                self.emit(Inst::Block);
+                self.emit(Inst::Loop);
+                if let Some(cond) = cond {
+                    self.gen_expr(*cond)?;
+                    self.emit(Inst::I64Eqz);
+                    self.emit(Inst::BrIf(1));
+                }
+                self.gen_stmt(*then)?;
+                if let Some(step) = step {
+                    self.gen_expr(*step)?;
+                    self.emit(Inst::Drop);
+                }
+                self.emit(Inst::Br(0));
+                self.emit(Inst::End);
+                self.emit(Inst::End);
+            }
+            Node::Block { stmts } => {
+                for stmt in stmts {
+                    self.gen_stmt(*stmt)?;
+                }
+            }
+            Node::Asm { .. } => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "inline asm is not supported on wasm32",
+                ))
+            }
+            // 式文の値はreturnが無いときの戻り値になる
+            expr => {
+                self.gen_expr(expr)?;
+                self.emit(Inst::LocalSet(Local::Ret));
+            }
+        }
+        Ok(())
+    }
+
+    fn gen_expr(&mut self, node: Node) -> io::Result<()> {
+        match node {
+            Node::Num(n) => self.emit(Inst::I64Const(n as i64)),
+            Node::Add { l, r } => self.binary(*l, *r, Inst::I64Add)?,
+            Node::Sub { l, r } => self.binary(*l, *r, Inst::I64Sub)?,
+            Node::Mul { l, r } => self.binary(*l, *r, Inst::I64Mul)?,
+            Node::Div { l, r } => self.binary(*l, *r, Inst::I64DivS)?,
+            Node::Eq { l, r } => self.compare(*l, *r, Inst::I64Eq)?,
+            Node::Ne { l, r } => self.compare(*l, *r, Inst::I64Ne)?,
+            Node::Lt { l, r } => self.compare(*l, *r, Inst::I64LtS)?,
+            Node::Le { l, r } => self.compare(*l, *r, Inst::I64LeS)?,
+            Node::Lvar(_) => {
+                self.lval(node);
+                self.emit(Inst::I64Load);
+            }
+            Node::Assign { l, r } => {
+                self.lval(*l);
+                self.gen_expr(*r)?;
+                self.emit(Inst::LocalTee(Local::Tmp));
+                self.emit(Inst::I64Store);
+                self.emit(Inst::LocalGet(Local::Tmp));
+            }
+            stmt => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("statement used as an expression: {}", stmt),
+                ))
+            }
+        }
+        Ok(())
+    }
+}
+
+// WAT (テキスト形式) を出力する
+pub struct WatCodeGen<W: Write> {
+    w: W,
+    selector: Selector,
+}
+
+impl<W: Write> CodeGen<W> for WatCodeGen<W> {
+    fn prefix(&mut self) -> io::Result<()> {
+        writeln!(self.w, "(module")?;
+        writeln!(self.w, "  (memory (export \"memory\") 1)")?;
+        writeln!(self.w, "  (global $sp (mut i32) (i32.const {}))", STACK_TOP)?;
+        writeln!(self.w, "  (func $main (export \"main\") (result i32)")?;
+        writeln!(self.w, "    (local $fp i32) (local $ret i64) (local $tmp i64)")?;
+        Ok(())
+    }
+
+    fn prologue(&mut self) -> io::Result<()> {
+        self.selector.prologue();
+        Ok(())
+    }
+
+    fn gen_from_nodes(&mut self, nodes: Nodes) -> io::Result<()> {
+        self.selector.gen_from_nodes(nodes)
+    }
+
+    fn epilogue(&mut self) -> io::Result<()> {
+        self.selector.epilogue();
+
+        // ブロックの入れ子に合わせて字下げする
+        let mut depth = 2;
+        for inst in self.selector.take() {
+            if matches!(inst, Inst::Else | Inst::End) {
+                depth -= 1;
+            }
+            writeln!(self.w, "{}{}", "  ".repeat(depth), inst)?;
+            if matches!(inst, Inst::Block | Inst::Loop | Inst::If | Inst::Else) {
+                depth += 1;
+            }
+        }
+        writeln!(self.w, "  )")?;
+        writeln!(self.w, ")")?;
+        Ok(())
+    }
+}
+
+impl<W: Write> WatCodeGen<W> {
+    pub fn new(w: W) -> Self {
+        Self {
+            w,
+            selector: Selector::new(),
+        }
+    }
+}
+
+// バイナリ形式の .wasm を出力する
+pub struct WasmCodeGen<W: Write> {
+    w: W,
+    selector: Selector,
+}
+
+impl<W: Write> CodeGen<W> for WasmCodeGen<W> {
+    fn prefix(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn prologue(&mut self) -> io::Result<()> {
+        self.selector.prologue();
+        Ok(())
+    }
+
+    fn gen_from_nodes(&mut self, nodes: Nodes) -> io::Result<()> {
+        self.selector.gen_from_nodes(nodes)
+    }
+
+    fn epilogue(&mut self) -> io::Result<()> {
+        self.selector.epilogue();
+        self.w.write_all(&encode_module(&self.selector.take()))
+    }
+}
+
+impl<W: Write> WasmCodeGen<W> {
+    pub fn new(w: W) -> Self {
+        Self {
+            w,
+            selector: Selector::new(),
+        }
+    }
+}
+
+const I32: u8 = 0x7f;
+const I64: u8 = 0x7e;
+const EMPTY_BLOCK: u8 = 0x40;
+
+// main関数だけを持つモジュールを組み立てる
+pub fn encode_module(insts: &[Inst]) -> Vec<u8> {
+    let mut out = vec![0x00, b'a', b's', b'm', 1, 0, 0, 0];
+
+    // type: () -> i32
+    section(&mut out, 1, &[1, 0x60, 0, 1, I32]);
+    // function: main は type 0
+    section(&mut out, 3, &[1, 0]);
+    // memory: 最小1ページ
+    section(&mut out, 5, &[1, 0x00, 1]);
+    // global: $sp
+    let mut global = vec![1, I32, 1, 0x41];
+    sleb128(&mut global, STACK_TOP as i64);
+    global.push(0x0b);
+    section(&mut out, 6, &global);
+    // export
+    let mut export = vec![2];
+    name(&mut export, "memory");
+    export.extend_from_slice(&[0x02, 0]);
+    name(&mut export, "main");
+    export.extend_from_slice(&[0x00, 0]);
+    section(&mut out, 7, &export);
+
+    // code
+    let mut body = vec![2, 1, I32, 2, I64];
+    for inst in insts {
+        encode_inst(&mut body, inst);
+    }
+    body.push(0x0b);
+    let mut code = vec![1];
+    uleb128(&mut code, body.len() as u64);
+    code.extend_from_slice(&body);
+    section(&mut out, 10, &code);
+
+    out
+}
+
+fn encode_inst(out: &mut Vec<u8>, inst: &Inst) {
+    match inst {
+        Inst::I32Const(n) => {
+            out.push(0x41);
+            sleb128(out, *n as i64);
+        }
+        Inst::I64Const(n) => {
+            out.push(0x42);
+            sleb128(out, *n);
+        }
+        Inst::I32Sub => out.push(0x6b),
+        Inst::I64Add => out.push(0x7c),
+        Inst::I64Sub => out.push(0x7d),
+        Inst::I64Mul => out.push(0x7e),
+        Inst::I64DivS => out.push(0x7f),
+        Inst::I64Eqz => out.push(0x50),
+        Inst::I64Eq => out.push(0x51),
+        Inst::I64Ne => out.push(0x52),
+        Inst::I64LtS => out.push(0x53),
+        Inst::I64LeS => out.push(0x57),
+        Inst::I64ExtendI32U => out.push(0xad),
+        Inst::I32WrapI64 => out.push(0xa7),
+        // memarg: align=2^3, offset=0
+        Inst::I64Load => out.extend_from_slice(&[0x29, 3, 0]),
+        Inst::I64Store => out.extend_from_slice(&[0x37, 3, 0]),
+        Inst::LocalGet(l) => {
+            out.push(0x20);
+            uleb128(out, l.index() as u64);
+        }
+        Inst::LocalSet(l) => {
+            out.push(0x21);
+            uleb128(out, l.index() as u64);
+        }
+        Inst::LocalTee(l) => {
+            out.push(0x22);
+            uleb128(out, l.index() as u64);
+        }
+        Inst::GlobalGetSp => out.extend_from_slice(&[0x23, 0]),
+        Inst::GlobalSetSp => out.extend_from_slice(&[0x24, 0]),
+        Inst::Block => out.extend_from_slice(&[0x02, EMPTY_BLOCK]),
+        Inst::Loop => out.extend_from_slice(&[0x03, EMPTY_BLOCK]),
+        Inst::If => out.extend_from_slice(&[0x04, EMPTY_BLOCK]),
+        Inst::Else => out.push(0x05),
+        Inst::End => out.push(0x0b),
+        Inst::Br(depth) => {
+            out.push(0x0c);
+            uleb128(out, *depth as u64);
+        }
+        Inst::BrIf(depth) => {
+            out.push(0x0d);
+            uleb128(out, *depth as u64);
+        }
+        Inst::Return => out.push(0x0f),
+        Inst::Drop => out.push(0x1a),
+    }
+}
+
+fn section(out: &mut Vec<u8>, id: u8, contents: &[u8]) {
+    out.push(id);
+    uleb128(out, contents.len() as u64);
+    out.extend_from_slice(contents);
+}
+
+fn name(out: &mut Vec<u8>, name: &str) {
+    uleb128(out, name.len() as u64);
+    out.extend_from_slice(name.as_bytes());
+}
+
+fn uleb128(out: &mut Vec<u8>, mut n: u64) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn sleb128(out: &mut Vec<u8>, mut n: i64) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        // 残りが符号ビットだけになったら終わり
+        if (n == 0 && byte & 0x40 == 0) || (n == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::parser::Parser;
+    use crate::lexer::tokenize;
+
+    use super::*;
+
+    #[test]
+    fn test_gen() {
+        let tokens =
+            tokenize(&mut "a = 3; while (a) a = a - 1; return a == 0;".chars().peekable()).unwrap();
+        let nodes = Parser::new(tokens.iter()).parse().unwrap();
+
+        let mut selector = Selector::new();
+        selector.gen_from_nodes(nodes).unwrap();
+
+        let expected = vec![
+            // a = 3;
+            Inst::LocalGet(Local::Fp),
+            Inst::I32Const(8),
+            Inst::I32Sub,
+            Inst::I64Const(3),
+            Inst::LocalTee(Local::Tmp),
+            Inst::I64Store,
+            Inst::LocalGet(Local::Tmp),
+            Inst::LocalSet(Local::Ret),
+            // while (a)
+            Inst::Block,
+            Inst::Loop,
+            Inst::LocalGet(Local::Fp),
+            Inst::I32Const(8),
+            Inst::I32Sub,
+            Inst::I64Load,
+            Inst::I64Eqz,
+            Inst::BrIf(1),
+            // a = a - 1;
+            Inst::LocalGet(Local::Fp),
+            Inst::I32Const(8),
+            Inst::I32Sub,
+            Inst::LocalGet(Local::Fp),
+            Inst::I32Const(8),
+            Inst::I32Sub,
+            Inst::I64Load,
+            Inst::I64Const(1),
+            Inst::I64Sub,
+            Inst::LocalTee(Local::Tmp),
+            Inst::I64Store,
+            Inst::LocalGet(Local::Tmp),
+            Inst::LocalSet(Local::Ret),
+            Inst::Br(0),
+            Inst::End,
+            Inst::End,
+            // return a == 0;
+            Inst::LocalGet(Local::Fp),
+            Inst::I32Const(8),
+            Inst::I32Sub,
+            Inst::I64Load,
+            Inst::I64Const(0),
+            Inst::I64Eq,
+            Inst::I64ExtendI32U,
+            Inst::I32WrapI64,
+            Inst::LocalGet(Local::Fp),
+            Inst::GlobalSetSp,
+            Inst::Return,
+        ];
+        assert_eq!(selector.take(), expected);
+    }
+
+    #[test]
+    fn test_encode_module() {
+        struct Test {
+            name: &'static str,
+            insts: Vec<Inst>,
+            expected: Vec<u8>,
+        }
+
+        let tests = vec![
+            Test {
+                name: "constants",
+                insts: vec![Inst::I32Const(-1), Inst::I64Const(65536), Inst::I64Const(63)],
+                expected: vec![0x41, 0x7f, 0x42, 0x80, 0x80, 0x04, 0x42, 0x3f],
+            },
+            Test {
+                name: "locals and memory",
+                insts: vec![
+                    Inst::LocalGet(Local::Fp),
+                    Inst::I64Load,
+                    Inst::LocalTee(Local::Tmp),
+                    Inst::LocalSet(Local::Ret),
+                ],
+                expected: vec![0x20, 0, 0x29, 3, 0, 0x22, 2, 0x21, 1],
+            },
+            Test {
+                name: "control flow",
+                insts: vec![
+                    Inst::Block,
+                    Inst::Loop,
+                    Inst::BrIf(1),
+                    Inst::Br(0),
+                    Inst::End,
+                    Inst::End,
+                ],
+                expected: vec![0x02, 0x40, 0x03, 0x40, 0x0d, 1, 0x0c, 0, 0x0b, 0x0b],
+            },
+        ];
+
+        for t in tests {
+            let module = encode_module(&t.insts);
+            // 関数本体は末尾の end の直前にある
+            let body = &module[module.len() - 1 - t.expected.len()..module.len() - 1];
+            assert_eq!(body, &t.expected[..], "Failed in the {}", t.name);
+        }
+    }
+
+    // .wasm を出力し、wasmi で main を呼んだ戻り値を確かめる
+    #[test]
+    fn test_run() {
+        struct Test {
+            name: &'static str,
+            input: &'static str,
+            expected: i32,
+        }
+
+        let tests = [
+            Test {
+                name: "arithmetic",
+                input: "(3+5)/2;",
+                expected: 4,
+            },
+            Test {
+                name: "return",
+                input: "a = 3; return a * 7; return 0;",
+                expected: 21,
+            },
+            Test {
+                name: "while",
+                input: "x = 0; i = 0; while (i < 10) {x = x + 2; i = i + 1;} return x;",
+                expected: 20,
+            },
+            Test {
+                name: "for step does not change the result",
+                input: "x = 5; for (i = 0; i < 3; i = i + 1) {}",
+                expected: 5,
+            },
+            Test {
+                name: "if else",
+                input: "if (1 < 2) 3; else 4;",
+                expected: 3,
+            },
+        ];
+
+        for t in tests {
+            let tokens = tokenize(&mut t.input.chars().peekable()).unwrap();
+            let nodes = Parser::new(tokens.iter()).parse().unwrap();
+            let mut bytes = Vec::new();
+            let mut gen = WasmCodeGen::new(&mut bytes);
+            gen.prefix().unwrap();
+            gen.prologue().unwrap();
+            gen.gen_from_nodes(nodes).unwrap();
+            gen.epilogue().unwrap();
+
+            let engine = wasmi::Engine::default();
+            let module = wasmi::Module::new(&engine, &bytes[..]).unwrap();
+            let mut store = wasmi::Store::new(&engine, ());
+            let instance = wasmi::Linker::<()>::new(&engine)
+                .instantiate(&mut store, &module)
+                .and_then(|pre| pre.start(&mut store))
+                .unwrap();
+            let main = instance.get_typed_func::<(), i32>(&store, "main").unwrap();
+            assert_eq!(main.call(&mut store, ()).unwrap(), t.expected, "Failed in the {}", t.name);
+        }
+    }
+}
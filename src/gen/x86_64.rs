@@ -0,0 +1,644 @@
+use std::fmt;
+use std::io;
+
+use crate::ast::node::LocalVar;
+use crate::gen::regalloc::{self, Allocation, Location};
+use crate::ast::node::substitute_asm_operands;
+use crate::gen::asm_registers;
+use crate::ir::{self, AsmArg, BinOp, BlockId, Function, Terminator, VReg};
+
+// 変数を置く領域の大きさ
+const VARS_SIZE: usize = 208; // FIXME: 208 / 8 = 26個しか変数宣言できない
+
+// 拡張asmの "r" オペランドに割り当てるレジスタ
+// asmをまたいで生きている仮想レジスタはこれらに割り当てないので、どれを使っても壊れない
+const ASM_REGISTERS: [Reg; 8] = [
+    Reg::R8,
+    Reg::R9,
+    Reg::R10,
+    Reg::R11,
+    Reg::Rcx,
+    Reg::Rdx,
+    Reg::Rsi,
+    Reg::Rdi,
+];
+
+// -O でレジスタ割り当てに使うレジスタ
+// rax と rdi は命令を選ぶときの作業用に空けておく
+// callee-savedは後ろに置き、使ったときだけフレームに退避する
+const ALLOCATABLE_REGISTERS: [Reg; 12] = [
+    Reg::Rsi,
+    Reg::Rcx,
+    Reg::Rdx,
+    Reg::R8,
+    Reg::R9,
+    Reg::R10,
+    Reg::R11,
+    Reg::Rbx,
+    Reg::R12,
+    Reg::R13,
+    Reg::R14,
+    Reg::R15,
+];
+
+const CALLEE_SAVED_REGISTERS: [Reg; 5] = [Reg::Rbx, Reg::R12, Reg::R13, Reg::R14, Reg::R15];
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum Reg {
+    Rax,
+    Rcx,
+    Rdx,
+    Rbx,
+    Rsp,
+    Rbp,
+    Rsi,
+    Rdi,
+    R8,
+    R9,
+    R10,
+    R11,
+    R12,
+    R13,
+    R14,
+    R15,
+}
+
+impl Reg {
+    // ModR/MやREXプレフィックスに入れるレジスタ番号
+    pub fn number(self) -> u8 {
+        self as u8
+    }
+
+    pub fn name(self) -> &'static str {
+        [
+            "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11",
+            "r12", "r13", "r14", "r15",
+        ][self as usize]
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Operand {
+    Reg(Reg),
+    Imm(i64),
+    Mem { base: Reg, disp: i32 }, // 64bitのメモリ [base+disp]
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Cond {
+    E,
+    Ne,
+    L,
+    Le,
+}
+
+impl Cond {
+    pub fn suffix(self) -> &'static str {
+        match self {
+            Cond::E => "e",
+            Cond::Ne => "ne",
+            Cond::L => "l",
+            Cond::Le => "le",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Inst {
+    Push(Operand),
+    Pop(Reg),
+    Mov(Operand, Operand),
+    Add(Reg, Operand),
+    Sub(Reg, Operand),
+    Imul(Reg, Reg),
+    Cqo,
+    Idiv(Reg),
+    Cmp(Reg, Operand),
+    Set(Cond),    // set<cc> al
+    Movzb(Reg),   // movzb reg, al
+    Jmp(String),
+    Je(String),
+    Call(String),
+    Label(String),
+    Ret,
+    Syscall,
+    // asmのテンプレートと %0, %1, ... に対応するオペランド
+    // 基本asmはテンプレートをそのまま出力する
+    InlineAsm {
+        template: String,
+        extended: bool,
+        operands: Vec<Operand>,
+    },
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Reg(r) => write!(f, "{}", r.name()),
+            Operand::Imm(n) => write!(f, "{}", n),
+            Operand::Mem { base, disp } if *disp < 0 => {
+                write!(f, "QWORD PTR [{}-{}]", base.name(), -(*disp as i64))
+            }
+            Operand::Mem { base, disp: 0 } => write!(f, "QWORD PTR [{}]", base.name()),
+            Operand::Mem { base, disp } => write!(f, "QWORD PTR [{}+{}]", base.name(), disp),
+        }
+    }
+}
+
+impl fmt::Display for Inst {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Inst::Push(op) => write!(f, "  push {}", op),
+            Inst::Pop(r) => write!(f, "  pop {}", r.name()),
+            Inst::Mov(dst, src) => write!(f, "  mov {}, {}", dst, src),
+            Inst::Add(dst, src) => write!(f, "  add {}, {}", dst.name(), src),
+            Inst::Sub(dst, src) => write!(f, "  sub {}, {}", dst.name(), src),
+            Inst::Imul(dst, src) => write!(f, "  imul {}, {}", dst.name(), src.name()),
+            Inst::Cqo => write!(f, "  cqo"),
+            Inst::Idiv(r) => write!(f, "  idiv {}", r.name()),
+            Inst::Cmp(l, r) => write!(f, "  cmp {}, {}", l.name(), r),
+            Inst::Set(cond) => write!(f, "  set{} al", cond.suffix()),
+            Inst::Movzb(r) => write!(f, "  movzb {}, al", r.name()),
+            Inst::Jmp(label) => write!(f, "  jmp {}", label),
+            Inst::Je(label) => write!(f, "  je  {}", label),
+            Inst::Call(symbol) => write!(f, "  call {}", symbol),
+            Inst::Label(label) => write!(f, "{}:", label),
+            Inst::Ret => write!(f, "  ret"),
+            Inst::Syscall => write!(f, "  syscall"),
+            Inst::InlineAsm {
+                template,
+                extended: false,
+                ..
+            } => write!(f, "{}", template),
+            Inst::InlineAsm { template, operands, .. } => {
+                let operands = operands.iter().map(|op| op.to_string()).collect::<Vec<_>>();
+                // オペランドの番号はパーサで検査している
+                let text = substitute_asm_operands(template, &operands).map_err(|_| fmt::Error)?;
+                write!(f, "{}", text)
+            }
+        }
+    }
+}
+
+// テキストで出力するときのアセンブリの文法
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum Syntax {
+    #[default]
+    Intel,
+    Att,
+}
+
+// 命令やオペランドをAT&T記法で表示する
+// オペランドは src, dst の順で、レジスタには % を、即値には $ を付ける
+pub struct Att<'a, T>(pub &'a T);
+
+impl fmt::Display for Att<'_, Operand> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Operand::Reg(r) => write!(f, "%{}", r.name()),
+            Operand::Imm(n) => write!(f, "${}", n),
+            Operand::Mem { base, disp: 0 } => write!(f, "(%{})", base.name()),
+            Operand::Mem { base, disp } => write!(f, "{}(%{})", disp, base.name()),
+        }
+    }
+}
+
+impl fmt::Display for Att<'_, Inst> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reg = |r: &Reg| Att(&Operand::Reg(*r)).to_string();
+        match self.0 {
+            Inst::Push(op) => write!(f, "  pushq {}", Att(op)),
+            Inst::Pop(r) => write!(f, "  popq {}", reg(r)),
+            Inst::Mov(dst, src) => write!(f, "  movq {}, {}", Att(src), Att(dst)),
+            Inst::Add(dst, src) => write!(f, "  addq {}, {}", Att(src), reg(dst)),
+            Inst::Sub(dst, src) => write!(f, "  subq {}, {}", Att(src), reg(dst)),
+            Inst::Imul(dst, src) => write!(f, "  imulq {}, {}", reg(src), reg(dst)),
+            Inst::Cqo => write!(f, "  cqto"),
+            Inst::Idiv(r) => write!(f, "  idivq {}", reg(r)),
+            Inst::Cmp(l, r) => write!(f, "  cmpq {}, {}", Att(r), reg(l)),
+            Inst::Set(cond) => write!(f, "  set{} %al", cond.suffix()),
+            Inst::Movzb(r) => write!(f, "  movzbq %al, {}", reg(r)),
+            Inst::InlineAsm {
+                template,
+                extended: true,
+                operands,
+            } => {
+                let operands = operands
+                    .iter()
+                    .map(|op| Att(op).to_string())
+                    .collect::<Vec<_>>();
+                let text = substitute_asm_operands(template, &operands).map_err(|_| fmt::Error)?;
+                write!(f, "{}", text)
+            }
+            // ラベルやジャンプはIntel記法と同じ
+            inst => write!(f, "{}", inst),
+        }
+    }
+}
+
+// IRからx86-64の命令列を選択する
+// 出力先(テキストのアセンブリ、機械語)によらず共通
+pub struct Selector {
+    insts: Vec<Inst>,
+    opt_level: u8,
+    alloc: Allocation,
+    saved: Vec<Reg>, // フレームに退避したcallee-savedのレジスタ
+}
+
+impl Default for Selector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Selector {
+    pub fn new() -> Self {
+        Self::with_opt_level(0)
+    }
+
+    // -O1 以上ではレジスタを割り当てる
+    pub fn with_opt_level(opt_level: u8) -> Self {
+        Self {
+            insts: Vec::new(),
+            opt_level,
+            alloc: Allocation::default(),
+            saved: Vec::new(),
+        }
+    }
+
+    // これまでに選択した命令を取り出す
+    pub fn take(&mut self) -> Vec<Inst> {
+        std::mem::take(&mut self.insts)
+    }
+
+    // 仮想レジスタはレジスタか変数の後ろのスタックに置き、
+    // 命令ごとに rax と rdi を作業用に使って計算する
+    // -O0 ではすべてスタックに置く
+    pub fn gen_function(&mut self, func: &Function) -> io::Result<()> {
+        self.alloc = if self.opt_level > 0 {
+            regalloc::linear_scan(func, &ALLOCATABLE_REGISTERS, clobbers)
+        } else {
+            Allocation::spill_all(func)
+        };
+        self.saved = self
+            .alloc
+            .regs
+            .iter()
+            .copied()
+            .filter(|r| CALLEE_SAVED_REGISTERS.contains(r))
+            .collect();
+
+        let frame_size = (VARS_SIZE + (1 + self.saved.len() + self.alloc.stack_slots) * 8).div_ceil(16) * 16;
+        self.emit(Inst::Push(Operand::Reg(Reg::Rbp)));
+        self.emit(Inst::Mov(Operand::Reg(Reg::Rbp), Operand::Reg(Reg::Rsp)));
+        self.emit(Inst::Sub(Reg::Rsp, Operand::Imm(frame_size as i64)));
+        for (i, reg) in self.saved.clone().into_iter().enumerate() {
+            self.emit(Inst::Mov(frame_slot(1 + i), Operand::Reg(reg)));
+        }
+        let uses_ret = func
+            .blocks
+            .iter()
+            .flat_map(|b| &b.insts)
+            .any(|inst| matches!(inst, ir::Inst::LoadRet { .. }));
+        if uses_ret {
+            self.emit(Inst::Mov(ret_slot(), Operand::Imm(0)));
+        }
+
+        for (i, block) in func.blocks.iter().enumerate() {
+            self.emit(Inst::Label(block_label(block.id)));
+            for inst in &block.insts {
+                self.inst(inst)?;
+            }
+            let next = func.blocks.get(i + 1).map(|b| b.id);
+            self.terminator(&block.term, next);
+        }
+        Ok(())
+    }
+
+    fn emit(&mut self, inst: Inst) {
+        self.insts.push(inst);
+    }
+
+    // 仮想レジスタの置き場所
+    fn operand(&self, v: VReg) -> Operand {
+        match self.alloc.location(v) {
+            Location::Reg(reg) => Operand::Reg(reg),
+            Location::Stack(n) => frame_slot(1 + self.saved.len() + n),
+        }
+    }
+
+    // 仮想レジスタがレジスタにあればそれを、無ければ scratch に読み出して返す
+    fn reg(&mut self, v: VReg, scratch: Reg) -> Reg {
+        match self.operand(v) {
+            Operand::Reg(reg) => reg,
+            _ => {
+                self.load(scratch, v);
+                scratch
+            }
+        }
+    }
+
+    fn load(&mut self, reg: Reg, v: VReg) {
+        let src = self.operand(v);
+        if src != Operand::Reg(reg) {
+            self.emit(Inst::Mov(Operand::Reg(reg), src));
+        }
+    }
+
+    fn store(&mut self, v: VReg, reg: Reg) {
+        let dst = self.operand(v);
+        if dst != Operand::Reg(reg) {
+            self.emit(Inst::Mov(dst, Operand::Reg(reg)));
+        }
+    }
+
+    fn inst(&mut self, inst: &ir::Inst) -> io::Result<()> {
+        match inst {
+            ir::Inst::Imm { dst, value } => {
+                // movでメモリに書ける即値は32bitの符号付き整数に限られる
+                let dst_operand = self.operand(*dst);
+                if matches!(dst_operand, Operand::Reg(_)) || i32::try_from(*value).is_ok() {
+                    self.emit(Inst::Mov(dst_operand, Operand::Imm(*value)));
+                } else {
+                    self.emit(Inst::Mov(Operand::Reg(Reg::Rax), Operand::Imm(*value)));
+                    self.store(*dst, Reg::Rax);
+                }
+            }
+            ir::Inst::Load { dst, var } => self.load_slot(*dst, var_slot(var)),
+            ir::Inst::Store { var, src } => self.store_slot(var_slot(var), *src),
+            ir::Inst::LoadRet { dst } => self.load_slot(*dst, ret_slot()),
+            ir::Inst::StoreRet { src } => self.store_slot(ret_slot(), *src),
+            ir::Inst::Bin { op, dst, l, r } => {
+                self.load(Reg::Rax, *l);
+                let r = self.reg(*r, Reg::Rdi);
+                match op {
+                    BinOp::Add => self.emit(Inst::Add(Reg::Rax, Operand::Reg(r))),
+                    BinOp::Sub => self.emit(Inst::Sub(Reg::Rax, Operand::Reg(r))),
+                    BinOp::Mul => self.emit(Inst::Imul(Reg::Rax, r)),
+                    BinOp::Div => {
+                        self.emit(Inst::Cqo);
+                        self.emit(Inst::Idiv(r));
+                    }
+                    BinOp::Eq => self.compare(Cond::E, r),
+                    BinOp::Ne => self.compare(Cond::Ne, r),
+                    BinOp::Lt => self.compare(Cond::L, r),
+                    BinOp::Le => self.compare(Cond::Le, r),
+                }
+                self.store(*dst, Reg::Rax);
+            }
+            ir::Inst::Asm {
+                template,
+                extended,
+                args,
+                clobbers,
+            } => return self.asm(template, *extended, args, clobbers),
+        }
+        Ok(())
+    }
+
+    // フレーム上のメモリと仮想レジスタの間でコピーする
+    fn load_slot(&mut self, dst: VReg, slot: Operand) {
+        match self.operand(dst) {
+            Operand::Reg(reg) => self.emit(Inst::Mov(Operand::Reg(reg), slot)),
+            _ => {
+                self.emit(Inst::Mov(Operand::Reg(Reg::Rax), slot));
+                self.store(dst, Reg::Rax);
+            }
+        }
+    }
+
+    fn store_slot(&mut self, slot: Operand, src: VReg) {
+        let src = self.reg(src, Reg::Rax);
+        self.emit(Inst::Mov(slot, Operand::Reg(src)));
+    }
+
+    fn compare(&mut self, cond: Cond, r: Reg) {
+        self.emit(Inst::Cmp(Reg::Rax, Operand::Reg(r)));
+        self.emit(Inst::Set(cond));
+        self.emit(Inst::Movzb(Reg::Rax));
+    }
+
+    // 次に置くブロックへのジャンプは省く
+    fn terminator(&mut self, term: &Terminator, next: Option<BlockId>) {
+        match term {
+            Terminator::Jmp(target) => {
+                if Some(*target) != next {
+                    self.emit(Inst::Jmp(block_label(*target)));
+                }
+            }
+            Terminator::Br { cond, then, els } => {
+                let cond = self.reg(*cond, Reg::Rax);
+                self.emit(Inst::Cmp(cond, Operand::Imm(0)));
+                self.emit(Inst::Je(block_label(*els)));
+                if Some(*then) != next {
+                    self.emit(Inst::Jmp(block_label(*then)));
+                }
+            }
+            Terminator::Ret(v) => {
+                self.load(Reg::Rax, *v);
+                for (i, reg) in self.saved.clone().into_iter().enumerate() {
+                    self.emit(Inst::Mov(Operand::Reg(reg), frame_slot(1 + i)));
+                }
+                self.emit(Inst::Mov(Operand::Reg(Reg::Rsp), Operand::Reg(Reg::Rbp)));
+                self.emit(Inst::Pop(Reg::Rbp));
+                self.emit(Inst::Ret);
+            }
+        }
+    }
+
+    fn asm(&mut self, template: &str, extended: bool, args: &[AsmArg], clobbers: &[String]) -> io::Result<()> {
+        let count = args
+            .iter()
+            .filter(|arg| matches!(arg, AsmArg::In(_) | AsmArg::Out(_) | AsmArg::InOut { .. }))
+            .count();
+        let mut registers = asm_registers(&ASM_REGISTERS, Reg::name, clobbers, count)?.into_iter();
+        let mut next_register = || registers.next().unwrap();
+
+        // オペランド %0, %1, ... を決め、入力をレジスタに載せる
+        let mut operands = Vec::new();
+        let mut stores = Vec::new();
+        for arg in args {
+            let operand = match arg {
+                AsmArg::In(src) => {
+                    let reg = next_register();
+                    self.load(reg, *src);
+                    Operand::Reg(reg)
+                }
+                AsmArg::Out(dst) => {
+                    let reg = next_register();
+                    stores.push((*dst, reg));
+                    Operand::Reg(reg)
+                }
+                AsmArg::InOut { src, dst } => {
+                    let reg = next_register();
+                    self.load(reg, *src);
+                    stores.push((*dst, reg));
+                    Operand::Reg(reg)
+                }
+                AsmArg::Mem(var) => var_slot(var),
+                AsmArg::Imm(n) => Operand::Imm(*n),
+            };
+            operands.push(operand);
+        }
+
+        self.emit(Inst::InlineAsm {
+            template: template.to_string(),
+            extended,
+            operands,
+        });
+
+        // 出力オペランドをレジスタから仮想レジスタに書き戻す
+        for (dst, reg) in stores {
+            self.store(dst, reg);
+        }
+        Ok(())
+    }
+}
+
+fn block_label(id: BlockId) -> String {
+    format!(".L{}", id)
+}
+
+// 変数 [rbp-offset]
+fn var_slot(var: &LocalVar) -> Operand {
+    Operand::Mem {
+        base: Reg::Rbp,
+        disp: -(var.offset as i32),
+    }
+}
+
+// 変数の領域の後ろの n 番目 [rbp-(208+8*(n+1))]
+// returnが無いときの戻り値、退避したレジスタ、スピルした仮想レジスタの順に置く
+fn frame_slot(n: usize) -> Operand {
+    Operand::Mem {
+        base: Reg::Rbp,
+        disp: -((VARS_SIZE + (n + 1) * 8) as i32),
+    }
+}
+
+// returnが無いときの戻り値 $ret
+fn ret_slot() -> Operand {
+    frame_slot(0)
+}
+
+// 命令が壊すレジスタ
+// そこで生きている仮想レジスタには割り当てない
+fn clobbers(inst: &ir::Inst) -> Vec<Reg> {
+    match inst {
+        // cqo が rdx を上書きする
+        ir::Inst::Bin { op: BinOp::Div, .. } => vec![Reg::Rdx],
+        // オペランドを載せるレジスタと、clobberに書かれたレジスタ
+        ir::Inst::Asm { clobbers, .. } => ASM_REGISTERS
+            .into_iter()
+            .chain(
+                ALLOCATABLE_REGISTERS
+                    .into_iter()
+                    .filter(|r| clobbers.iter().any(|c| c == r.name())),
+            )
+            .collect(),
+        _ => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::parser::Parser;
+    use crate::lexer::tokenize;
+
+    use super::*;
+
+    #[test]
+    fn test_att() {
+        struct Test {
+            name: &'static str,
+            inst: Inst,
+            expected: &'static str,
+        }
+
+        let tests = vec![
+            Test {
+                name: "push immediate",
+                inst: Inst::Push(Operand::Imm(42)),
+                expected: "  pushq $42",
+            },
+            Test {
+                name: "load from memory",
+                inst: Inst::Mov(Operand::Reg(Reg::Rax), Operand::Mem { base: Reg::Rax, disp: 0 }),
+                expected: "  movq (%rax), %rax",
+            },
+            Test {
+                name: "store to a local variable",
+                inst: Inst::Mov(Operand::Mem { base: Reg::Rbp, disp: -8 }, Operand::Reg(Reg::R8)),
+                expected: "  movq %r8, -8(%rbp)",
+            },
+            Test {
+                name: "sub",
+                inst: Inst::Sub(Reg::Rsp, Operand::Imm(208)),
+                expected: "  subq $208, %rsp",
+            },
+            Test {
+                name: "compare",
+                inst: Inst::Cmp(Reg::Rax, Operand::Reg(Reg::Rdi)),
+                expected: "  cmpq %rdi, %rax",
+            },
+            Test {
+                name: "movzb",
+                inst: Inst::Movzb(Reg::Rax),
+                expected: "  movzbq %al, %rax",
+            },
+            Test {
+                name: "label",
+                inst: Inst::Label(".L0".to_string()),
+                expected: ".L0:",
+            },
+            Test {
+                name: "inline asm",
+                inst: Inst::InlineAsm {
+                    template: "  addq %1, %0\n  movq %0, %%rax".to_string(),
+                    extended: true,
+                    operands: vec![Operand::Reg(Reg::R8), Operand::Imm(5)],
+                },
+                expected: "  addq $5, %r8\n  movq %r8, %rax",
+            },
+            Test {
+                name: "basic asm",
+                inst: Inst::InlineAsm {
+                    template: "  # 100%1 %%".to_string(),
+                    extended: false,
+                    operands: vec![],
+                },
+                expected: "  # 100%1 %%",
+            },
+        ];
+
+        for t in tests {
+            assert_eq!(Att(&t.inst).to_string(), t.expected, "Failed in the {}", t.name);
+        }
+    }
+
+    #[test]
+    fn test_asm_error() {
+        struct Test {
+            name: &'static str,
+            input: &'static str,
+        }
+
+        let tests = [
+            Test {
+                name: "too many register operands",
+                input: "asm(\"\" : : \"r\"(1), \"r\"(2), \"r\"(3), \"r\"(4), \"r\"(5), \"r\"(6), \"r\"(7), \"r\"(8), \"r\"(9));",
+            },
+            Test {
+                name: "registers taken by clobbers",
+                input: "asm(\"\" : : \"r\"(1), \"r\"(2), \"r\"(3), \"r\"(4), \"r\"(5), \"r\"(6), \"r\"(7) : \"r8\", \"rdi\");",
+            },
+        ];
+
+        for t in tests {
+            let tokens = tokenize(&mut t.input.chars().peekable()).unwrap();
+            let nodes = Parser::new(tokens.iter()).parse().unwrap();
+            let err = Selector::new().gen_function(&ir::lower(&nodes)).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "Failed in the {}", t.name);
+        }
+    }
+}
@@ -0,0 +1,273 @@
+use thiserror::Error;
+
+use crate::ast::node::{LocalVar, Node, Nodes};
+
+// ネイティブのバックエンドと同じく、フレームは208バイト (変数26個分)
+const FRAME_SIZE: usize = 208;
+const WORD_SIZE: usize = 8;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum InterpError {
+    #[error("read of uninitialized variable: {0}")]
+    UninitializedVariable(String),
+    #[error("stack frame overflow: {0} is outside of the frame")]
+    FrameOverflow(String),
+    #[error("division by zero")]
+    DivisionByZero,
+    #[error("signed integer overflow")]
+    Overflow,
+    #[error("inline asm cannot be interpreted")]
+    InlineAsm,
+    #[error("statement used as an expression: {0}")]
+    NotExpression(String),
+}
+
+type Result<T> = std::result::Result<T, InterpError>;
+
+// 文を実行した結果
+enum Flow {
+    Normal,
+    Return(i64),
+}
+
+// ASTをそのまま実行する
+// 変数はフレームを模したメモリに置き、未定義動作になる操作はエラーにして止める
+pub struct Interpreter {
+    frame: Vec<Option<i64>>, // 8バイトごとのスロット、Noneは未初期化
+    last: i64,               // 最後に評価した式文の値 (returnが無いときの戻り値)
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        Self {
+            frame: vec![None; FRAME_SIZE / WORD_SIZE],
+            last: 0,
+        }
+    }
+
+    // プログラムを実行して main の戻り値を返す
+    pub fn run(&mut self, nodes: &Nodes) -> Result<i64> {
+        for node in &nodes.0 {
+            if let Flow::Return(value) = self.stmt(node)? {
+                return Ok(value);
+            }
+        }
+        Ok(self.last)
+    }
+
+    fn stmt(&mut self, node: &Node) -> Result<Flow> {
+        match node {
+            Node::Return { expr } => return Ok(Flow::Return(self.expr(expr)?)),
+            Node::If { cond, then, els } => {
+                if self.expr(cond)? != 0 {
+                    return self.stmt(then);
+                } else if let Some(els) = els {
+                    return self.stmt(els);
+                }
+            }
+            Node::While { cond, then } => {
+                while self.expr(cond)? != 0 {
+                    if let Flow::Return(value) = self.stmt(then)? {
+                        return Ok(Flow::Return(value));
+                    }
+                }
+            }
+            Node::For {
+                init,
+                cond,
+                step,
+                then,
+            } => {
+                if let Some(init) = init {
+                    self.expr(init)?;
+                }
+                // 条件式が無ければ無限ループ
+                while match cond {
+                    Some(cond) => self.expr(cond)? != 0,
+                    None => true,
+                } {
+                    if let Flow::Return(value) = self.stmt(then)? {
+                        return Ok(Flow::Return(value));
+                    }
+                    if let Some(step) = step {
+                        self.expr(step)?;
+                    }
+                }
+            }
+            Node::Block { stmts } => {
+                for stmt in stmts {
+                    if let Flow::Return(value) = self.stmt(stmt)? {
+                        return Ok(Flow::Return(value));
+                    }
+                }
+            }
+            Node::Asm { .. } => return Err(InterpError::InlineAsm),
+            expr => self.last = self.expr(expr)?,
+        }
+        Ok(Flow::Normal)
+    }
+
+    fn expr(&mut self, node: &Node) -> Result<i64> {
+        match node {
+            Node::Num(n) => Ok(*n as i64),
+            Node::Lvar(var) => {
+                let slot = self.slot(var)?;
+                self.frame[slot].ok_or_else(|| InterpError::UninitializedVariable(var.ident.to_string()))
+            }
+            Node::Assign { l, r } => {
+                let Node::Lvar(var) = &**l else {
+                    panic!("代入の左辺値が変数ではありません");
+                };
+                let slot = self.slot(var)?;
+                let value = self.expr(r)?;
+                self.frame[slot] = Some(value);
+                Ok(value)
+            }
+            Node::Add { l, r } => self.expr(l)?.checked_add(self.expr(r)?).ok_or(InterpError::Overflow),
+            Node::Sub { l, r } => self.expr(l)?.checked_sub(self.expr(r)?).ok_or(InterpError::Overflow),
+            Node::Mul { l, r } => self.expr(l)?.checked_mul(self.expr(r)?).ok_or(InterpError::Overflow),
+            Node::Div { l, r } => {
+                let (l, r) = (self.expr(l)?, self.expr(r)?);
+                if r == 0 {
+                    return Err(InterpError::DivisionByZero);
+                }
+                l.checked_div(r).ok_or(InterpError::Overflow)
+            }
+            Node::Lt { l, r } => Ok((self.expr(l)? < self.expr(r)?) as i64),
+            Node::Le { l, r } => Ok((self.expr(l)? <= self.expr(r)?) as i64),
+            Node::Eq { l, r } => Ok((self.expr(l)? == self.expr(r)?) as i64),
+            Node::Ne { l, r } => Ok((self.expr(l)? != self.expr(r)?) as i64),
+            stmt => Err(InterpError::NotExpression(stmt.to_string())),
+        }
+    }
+
+    // 変数のオフセット [rbp-offset] をスロットの番号にする
+    fn slot(&self, LocalVar { ident, offset }: &LocalVar) -> Result<usize> {
+        if *offset == 0 || *offset > FRAME_SIZE {
+            return Err(InterpError::FrameOverflow(ident.to_string()));
+        }
+        Ok(offset / WORD_SIZE - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::parser::Parser;
+    use crate::lexer::tokenize;
+
+    use super::*;
+
+    fn run(input: &str) -> Result<i64> {
+        let tokens = tokenize(&mut input.chars().peekable()).unwrap();
+        let nodes = Parser::new(tokens.iter()).parse().unwrap();
+        Interpreter::new().run(&nodes)
+    }
+
+    #[test]
+    fn test_run() {
+        struct Test {
+            name: &'static str,
+            input: &'static str,
+            expected: i64,
+        }
+
+        let tests = vec![
+            Test {
+                name: "arithmetic",
+                input: "5+6*7;",
+                expected: 47,
+            },
+            Test {
+                name: "unary and comparison",
+                input: "-3 + 5 == 2; 1 >= 2;",
+                expected: 0,
+            },
+            Test {
+                name: "variables",
+                input: "a = b = 3; return a * b;",
+                expected: 9,
+            },
+            Test {
+                name: "if else",
+                input: "if (1 > 2) return 7; else return 14; return 0;",
+                expected: 14,
+            },
+            Test {
+                name: "while",
+                input: "x = 0; i = 0; while (i < 10) {x = x + 2; i = i + 1;} return x;",
+                expected: 20,
+            },
+            Test {
+                name: "for",
+                input: "x = 0; for (i = 0; i < 10; i = i + 1) {x = x + 1;} return x;",
+                expected: 10,
+            },
+            Test {
+                name: "for without condition",
+                input: "for (i = 0; ; i = i + 1) if (i == 7) return i;",
+                expected: 7,
+            },
+            Test {
+                name: "return inside a block",
+                input: "x = 1; { x = 2; return x; x = 3; } return x;",
+                expected: 2,
+            },
+            Test {
+                name: "large value",
+                input: "x = 3000000000; return x * 2;",
+                expected: 6000000000,
+            },
+        ];
+
+        for t in tests {
+            assert_eq!(run(t.input), Ok(t.expected), "Failed in the {}", t.name);
+        }
+    }
+
+    #[test]
+    fn test_run_error() {
+        struct Test {
+            name: &'static str,
+            input: &'static str,
+            expected: InterpError,
+        }
+
+        let tests = vec![
+            Test {
+                name: "uninitialized variable",
+                input: "a = 1; return a + b;",
+                expected: InterpError::UninitializedVariable("b".to_string()),
+            },
+            Test {
+                name: "division by zero",
+                input: "a = 0; return 1 / a;",
+                expected: InterpError::DivisionByZero,
+            },
+            Test {
+                name: "overflow",
+                input: "a = 3000000000; return a * a * a;",
+                expected: InterpError::Overflow,
+            },
+            Test {
+                name: "inline asm",
+                input: "asm(\"nop\");",
+                expected: InterpError::InlineAsm,
+            },
+            Test {
+                name: "too many variables",
+                input: "a=1;b=1;c=1;d=1;e=1;f=1;g=1;h=1;i=1;j=1;k=1;l=1;m=1;n=1;o=1;p=1;q=1;r=1;s=1;t=1;u=1;v=1;w=1;x=1;y=1;z=1;aa=1;",
+                expected: InterpError::FrameOverflow("aa".to_string()),
+            },
+        ];
+
+        for t in tests {
+            assert_eq!(run(t.input), Err(t.expected), "Failed in the {}", t.name);
+        }
+    }
+}
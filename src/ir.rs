@@ -0,0 +1,602 @@
+use std::fmt;
+
+use crate::ast::node::{AsmOperand, LocalVar, Node, Nodes};
+
+// 仮想レジスタ
+// 数に制限は無く、バックエンドが物理レジスタかスタックに割り当てる
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub struct VReg(pub usize);
+
+impl fmt::Display for VReg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "%{}", self.0)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub struct BlockId(pub usize);
+
+impl fmt::Display for BlockId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "bb{}", self.0)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+}
+
+impl BinOp {
+    pub fn name(self) -> &'static str {
+        match self {
+            BinOp::Add => "add",
+            BinOp::Sub => "sub",
+            BinOp::Mul => "mul",
+            BinOp::Div => "div",
+            BinOp::Eq => "eq",
+            BinOp::Ne => "ne",
+            BinOp::Lt => "lt",
+            BinOp::Le => "le",
+        }
+    }
+}
+
+// 拡張asmのオペランド
+// 番号はASTと同じく出力、入力の順
+#[derive(Debug, PartialEq, Clone)]
+pub enum AsmArg {
+    In(VReg),                       // "r" の入力
+    Out(VReg),                      // "=r" の出力
+    InOut { src: VReg, dst: VReg }, // "+r" はsrcを読み、結果をdstに書く
+    Mem(LocalVar),                  // "m" は変数のメモリ
+    Imm(i64),                       // 定数式
+}
+
+// 三番地コード
+// 変数はフレーム上のメモリに置き、load と store でだけ読み書きする
+// returnが無いときの戻り値もフレーム上の専用の領域 $ret に置き、0 から始まる
+#[derive(Debug, PartialEq, Clone)]
+pub enum Inst {
+    Imm {
+        dst: VReg,
+        value: i64,
+    },
+    Load {
+        dst: VReg,
+        var: LocalVar,
+    },
+    Store {
+        var: LocalVar,
+        src: VReg,
+    },
+    LoadRet {
+        dst: VReg,
+    },
+    StoreRet {
+        src: VReg,
+    },
+    Bin {
+        op: BinOp,
+        dst: VReg,
+        l: VReg,
+        r: VReg,
+    },
+    Asm {
+        template: String,
+        extended: bool,
+        args: Vec<AsmArg>,
+        clobbers: Vec<String>,
+    },
+}
+
+// 基本ブロックの最後の命令
+#[derive(Debug, PartialEq, Clone)]
+pub enum Terminator {
+    Jmp(BlockId),
+    Br { cond: VReg, then: BlockId, els: BlockId }, // condが0でなければthen
+    Ret(VReg),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Block {
+    pub id: BlockId,
+    pub insts: Vec<Inst>,
+    pub term: Terminator,
+}
+
+// blocks の先頭が入口で、並びはそのまま出力するときの順番
+#[derive(Debug, PartialEq, Clone)]
+pub struct Function {
+    pub blocks: Vec<Block>,
+    pub vregs: usize, // 使った仮想レジスタの数
+}
+
+impl Inst {
+    // この命令が読む仮想レジスタ
+    pub fn uses(&self) -> Vec<VReg> {
+        match self {
+            Inst::Imm { .. } | Inst::Load { .. } | Inst::LoadRet { .. } => vec![],
+            Inst::Store { src, .. } | Inst::StoreRet { src } => vec![*src],
+            Inst::Bin { l, r, .. } => vec![*l, *r],
+            Inst::Asm { args, .. } => args
+                .iter()
+                .filter_map(|arg| match arg {
+                    AsmArg::In(src) | AsmArg::InOut { src, .. } => Some(*src),
+                    _ => None,
+                })
+                .collect(),
+        }
+    }
+
+    // この命令が書く仮想レジスタ
+    pub fn defs(&self) -> Vec<VReg> {
+        match self {
+            Inst::Imm { dst, .. } | Inst::Load { dst, .. } | Inst::LoadRet { dst } | Inst::Bin { dst, .. } => {
+                vec![*dst]
+            }
+            Inst::Store { .. } | Inst::StoreRet { .. } => vec![],
+            Inst::Asm { args, .. } => args
+                .iter()
+                .filter_map(|arg| match arg {
+                    AsmArg::Out(dst) | AsmArg::InOut { dst, .. } => Some(*dst),
+                    _ => None,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Terminator {
+    pub fn uses(&self) -> Vec<VReg> {
+        match self {
+            Terminator::Jmp(_) => vec![],
+            Terminator::Br { cond, .. } => vec![*cond],
+            Terminator::Ret(v) => vec![*v],
+        }
+    }
+
+    pub fn successors(&self) -> Vec<BlockId> {
+        match self {
+            Terminator::Jmp(target) => vec![*target],
+            Terminator::Br { then, els, .. } => vec![*then, *els],
+            Terminator::Ret(_) => vec![],
+        }
+    }
+}
+
+// ASTをIRに変換する
+// returnが無いときは最後に実行した式文の値を返す
+// 式文の値は実行した経路によって変わるので、そのたびに $ret に書いておく
+pub fn lower(nodes: &Nodes) -> Function {
+    let mut lowerer = Lowerer {
+        blocks: Vec::new(),
+        current: BlockId(0),
+        insts: Vec::new(),
+        next_block: 1,
+        vregs: 0,
+        uses_ret: false,
+    };
+    for node in &nodes.0 {
+        lowerer.stmt(node);
+    }
+    let ret = if lowerer.uses_ret {
+        let dst = lowerer.vreg();
+        lowerer.emit(Inst::LoadRet { dst });
+        dst
+    } else {
+        lowerer.imm(0)
+    };
+    lowerer.finish(Terminator::Ret(ret));
+
+    Function {
+        blocks: lowerer.blocks,
+        vregs: lowerer.vregs,
+    }
+}
+
+struct Lowerer {
+    blocks: Vec<Block>,
+    current: BlockId, // 命令を追加しているブロック
+    insts: Vec<Inst>,
+    next_block: usize,
+    vregs: usize,
+    uses_ret: bool, // 式文の値を $ret に書いたか
+}
+
+impl Lowerer {
+    fn vreg(&mut self) -> VReg {
+        let v = VReg(self.vregs);
+        self.vregs += 1;
+        v
+    }
+
+    fn new_block(&mut self) -> BlockId {
+        let id = BlockId(self.next_block);
+        self.next_block += 1;
+        id
+    }
+
+    fn emit(&mut self, inst: Inst) {
+        self.insts.push(inst);
+    }
+
+    // 今のブロックを閉じる
+    fn finish(&mut self, term: Terminator) {
+        self.blocks.push(Block {
+            id: self.current,
+            insts: std::mem::take(&mut self.insts),
+            term,
+        });
+    }
+
+    fn start(&mut self, id: BlockId) {
+        self.current = id;
+    }
+
+    fn imm(&mut self, value: i64) -> VReg {
+        let dst = self.vreg();
+        self.emit(Inst::Imm { dst, value });
+        dst
+    }
+
+    fn stmt(&mut self, node: &Node) {
+        match node {
+            Node::Return { expr } => {
+                let v = self.expr(expr);
+                self.finish(Terminator::Ret(v));
+                // 以降の文は到達しないブロックに置く
+                let dead = self.new_block();
+                self.start(dead);
+            }
+            Node::If { cond, then, els } => {
+                let cond = self.expr(cond);
+                let then_block = self.new_block();
+                let end = self.new_block();
+                let els_block = if els.is_some() { self.new_block() } else { end };
+                self.finish(Terminator::Br {
+                    cond,
+                    then: then_block,
+                    els: els_block,
+                });
+                self.start(then_block);
+                self.stmt(then);
+                self.finish(Terminator::Jmp(end));
+                if let Some(els) = els {
+                    self.start(els_block);
+                    self.stmt(els);
+                    self.finish(Terminator::Jmp(end));
+                }
+                self.start(end);
+            }
+            Node::While { cond, then } => self.lower_loop(Some(cond), None, then),
+            Node::For {
+                init,
+                cond,
+                step,
+                then,
+            } => {
+                if let Some(init) = init {
+                    self.expr(init);
+                }
+                self.lower_loop(cond.as_deref(), step.as_deref(), then);
+            }
+            Node::Block { stmts } => {
+                for stmt in stmts {
+                    self.stmt(stmt);
+                }
+            }
+            Node::Asm {
+                template,
+                extended,
+                outputs,
+                inputs,
+                clobbers,
+            } => self.asm(template, *extended, outputs, inputs, clobbers),
+            expr => {
+                let src = self.expr(expr);
+                self.emit(Inst::StoreRet { src });
+                self.uses_ret = true;
+            }
+        }
+    }
+
+    // 条件式が無ければ無限ループ
+    fn lower_loop(&mut self, cond: Option<&Node>, step: Option<&Node>, then: &Node) {
+        let begin = self.new_block();
+        let body = self.new_block();
+        let end = self.new_block();
+        self.finish(Terminator::Jmp(begin));
+
+        self.start(begin);
+        match cond {
+            Some(cond) => {
+                let cond = self.expr(cond);
+                self.finish(Terminator::Br {
+                    cond,
+                    then: body,
+                    els: end,
+                });
+            }
+            None => self.finish(Terminator::Jmp(body)),
+        }
+
+        self.start(body);
+        self.stmt(then);
+        if let Some(step) = step {
+            self.expr(step);
+        }
+        self.finish(Terminator::Jmp(begin));
+
+        self.start(end);
+    }
+
+    fn expr(&mut self, node: &Node) -> VReg {
+        match node {
+            Node::Num(n) => self.imm(*n as i64),
+            Node::Lvar(var) => {
+                let dst = self.vreg();
+                self.emit(Inst::Load {
+                    dst,
+                    var: var.clone(),
+                });
+                dst
+            }
+            Node::Assign { l, r } => {
+                let Node::Lvar(var) = &**l else {
+                    panic!("代入の左辺値が変数ではありません");
+                };
+                let src = self.expr(r);
+                self.emit(Inst::Store {
+                    var: var.clone(),
+                    src,
+                });
+                src
+            }
+            Node::Add { l, r } => self.binary(BinOp::Add, l, r),
+            Node::Sub { l, r } => self.binary(BinOp::Sub, l, r),
+            Node::Mul { l, r } => self.binary(BinOp::Mul, l, r),
+            Node::Div { l, r } => self.binary(BinOp::Div, l, r),
+            Node::Eq { l, r } => self.binary(BinOp::Eq, l, r),
+            Node::Ne { l, r } => self.binary(BinOp::Ne, l, r),
+            Node::Lt { l, r } => self.binary(BinOp::Lt, l, r),
+            Node::Le { l, r } => self.binary(BinOp::Le, l, r),
+            stmt => panic!("式ではありません: {}", stmt),
+        }
+    }
+
+    fn binary(&mut self, op: BinOp, l: &Node, r: &Node) -> VReg {
+        let l = self.expr(l);
+        let r = self.expr(r);
+        let dst = self.vreg();
+        self.emit(Inst::Bin { op, dst, l, r });
+        dst
+    }
+
+    fn asm(&mut self, template: &str, extended: bool, outputs: &[AsmOperand], inputs: &[AsmOperand], clobbers: &[String]) {
+        let n_outputs = outputs.len();
+        let mut args = Vec::new();
+        let mut stores = Vec::new();
+        for (i, AsmOperand { constraint, expr }) in outputs.iter().chain(inputs).enumerate() {
+            let is_output = i < n_outputs;
+            let arg = match &**expr {
+                _ if constraint.contains('r') && !is_output => AsmArg::In(self.expr(expr)),
+                _ if constraint.contains('r') => {
+                    let src = constraint.contains('+').then(|| self.expr(expr));
+                    let dst = self.vreg();
+                    if let Node::Lvar(var) = &**expr {
+                        stores.push((var.clone(), dst));
+                    }
+                    match src {
+                        Some(src) => AsmArg::InOut { src, dst },
+                        None => AsmArg::Out(dst),
+                    }
+                }
+                Node::Lvar(var) => AsmArg::Mem(var.clone()),
+                _ => AsmArg::Imm(expr.eval_const().expect("asmのオペランドが不正です")),
+            };
+            args.push(arg);
+        }
+
+        self.emit(Inst::Asm {
+            template: template.to_string(),
+            extended,
+            args,
+            clobbers: clobbers.to_vec(),
+        });
+
+        // 出力オペランドを変数に書き戻す
+        for (var, src) in stores {
+            self.emit(Inst::Store { var, src });
+        }
+    }
+}
+
+impl fmt::Display for AsmArg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AsmArg::In(src) => write!(f, "in {}", src),
+            AsmArg::Out(dst) => write!(f, "out {}", dst),
+            AsmArg::InOut { src, dst } => write!(f, "inout {} -> {}", src, dst),
+            AsmArg::Mem(var) => write!(f, "mem {}", var.ident),
+            AsmArg::Imm(n) => write!(f, "imm {}", n),
+        }
+    }
+}
+
+impl fmt::Display for Inst {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Inst::Imm { dst, value } => write!(f, "  {} = imm {}", dst, value),
+            Inst::Load { dst, var } => write!(f, "  {} = load {}", dst, var.ident),
+            Inst::Store { var, src } => write!(f, "  store {}, {}", var.ident, src),
+            Inst::LoadRet { dst } => write!(f, "  {} = load $ret", dst),
+            Inst::StoreRet { src } => write!(f, "  store $ret, {}", src),
+            Inst::Bin { op, dst, l, r } => write!(f, "  {} = {} {}, {}", dst, op.name(), l, r),
+            Inst::Asm {
+                template,
+                args,
+                clobbers,
+                ..
+            } => {
+                write!(f, "  asm {:?}", template)?;
+                for (i, arg) in args.iter().enumerate() {
+                    write!(f, "{} {}", if i == 0 { "" } else { "," }, arg)?;
+                }
+                if !clobbers.is_empty() {
+                    write!(f, " clobbers {}", clobbers.join(", "))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl fmt::Display for Terminator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Terminator::Jmp(target) => write!(f, "  jmp {}", target),
+            Terminator::Br { cond, then, els } => write!(f, "  br {}, {}, {}", cond, then, els),
+            Terminator::Ret(v) => write!(f, "  ret {}", v),
+        }
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "fn main {{")?;
+        for block in &self.blocks {
+            writeln!(f, "{}:", block.id)?;
+            for inst in &block.insts {
+                writeln!(f, "{}", inst)?;
+            }
+            writeln!(f, "{}", block.term)?;
+        }
+        writeln!(f, "}}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::parser::Parser;
+    use crate::lexer::tokenize;
+
+    use super::*;
+
+    #[test]
+    fn test_lower() {
+        struct Test {
+            name: &'static str,
+            input: &'static str,
+            expected: &'static str,
+        }
+
+        let tests = vec![
+            Test {
+                name: "arithmetic",
+                input: "5+6*7;",
+                expected: "fn main {
+bb0:
+  %0 = imm 5
+  %1 = imm 6
+  %2 = imm 7
+  %3 = mul %1, %2
+  %4 = add %0, %3
+  store $ret, %4
+  %5 = load $ret
+  ret %5
+}
+",
+            },
+            Test {
+                name: "variables",
+                input: "a = 3; return a - 1;",
+                expected: "fn main {
+bb0:
+  %0 = imm 3
+  store a, %0
+  store $ret, %0
+  %1 = load a
+  %2 = imm 1
+  %3 = sub %1, %2
+  ret %3
+bb1:
+  %4 = load $ret
+  ret %4
+}
+",
+            },
+            Test {
+                name: "if else",
+                input: "if (1 < 2) 3; else 4;",
+                expected: "fn main {
+bb0:
+  %0 = imm 1
+  %1 = imm 2
+  %2 = lt %0, %1
+  br %2, bb1, bb3
+bb1:
+  %3 = imm 3
+  store $ret, %3
+  jmp bb2
+bb3:
+  %4 = imm 4
+  store $ret, %4
+  jmp bb2
+bb2:
+  %5 = load $ret
+  ret %5
+}
+",
+            },
+            Test {
+                name: "for without condition",
+                input: "for (;;) 1;",
+                expected: "fn main {
+bb0:
+  jmp bb1
+bb1:
+  jmp bb2
+bb2:
+  %0 = imm 1
+  store $ret, %0
+  jmp bb1
+bb3:
+  %1 = load $ret
+  ret %1
+}
+",
+            },
+            Test {
+                name: "asm",
+                input: "a = 1; asm(\"add %0, %1\" : \"+r\"(a) : \"r\"(2), \"m\"(a), \"i\"(3) : \"rcx\");",
+                expected: "fn main {
+bb0:
+  %0 = imm 1
+  store a, %0
+  store $ret, %0
+  %1 = load a
+  %3 = imm 2
+  asm \"add %0, %1\" inout %1 -> %2, in %3, mem a, imm 3 clobbers rcx
+  store a, %2
+  %4 = load $ret
+  ret %4
+}
+",
+            },
+        ];
+
+        for t in tests {
+            let tokens = tokenize(&mut t.input.chars().peekable()).unwrap();
+            let nodes = Parser::new(tokens.iter()).parse().unwrap();
+            assert_eq!(lower(&nodes).to_string(), t.expected, "Failed in the {}", t.name);
+        }
+    }
+}
@@ -0,0 +1,181 @@
+use std::io;
+
+use crate::ast::node::Nodes;
+use crate::gen::encode::encode;
+use crate::gen::x86_64::Selector;
+use crate::ir;
+
+// このホストで機械語を直接実行できるか
+pub const SUPPORTED: bool = cfg!(all(target_arch = "x86_64", target_os = "linux"));
+
+// AsmCodeGenと同じ命令選択で機械語を作り、実行可能なメモリに置いて main を呼ぶ
+pub fn run(nodes: Nodes, opt_level: u8) -> io::Result<i64> {
+    let mut selector = Selector::with_opt_level(opt_level);
+    selector.gen_function(&ir::lower(&nodes))?;
+
+    let assembled = encode(&selector.take())?;
+    // 外部の関数を呼ばないので再配置は残らないはず
+    if let Some(r) = assembled.relocations.first() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("cannot resolve symbol in JIT: {}", r.symbol),
+        ));
+    }
+
+    let code = ExecutableBuffer::new(&assembled.code)?;
+    Ok(code.call())
+}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+mod sys {
+    use std::ffi::c_void;
+
+    pub const PROT_READ: i32 = 0x1;
+    pub const PROT_WRITE: i32 = 0x2;
+    pub const PROT_EXEC: i32 = 0x4;
+    pub const MAP_PRIVATE: i32 = 0x02;
+    pub const MAP_ANONYMOUS: i32 = 0x20;
+    pub const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+
+    extern "C" {
+        pub fn mmap(
+            addr: *mut c_void,
+            len: usize,
+            prot: i32,
+            flags: i32,
+            fd: i32,
+            offset: i64,
+        ) -> *mut c_void;
+        pub fn mprotect(addr: *mut c_void, len: usize, prot: i32) -> i32;
+        pub fn munmap(addr: *mut c_void, len: usize) -> i32;
+    }
+}
+
+// mmapした実行可能なメモリ
+// 書き込みと実行を同時に許さないよう、コピーしてから読み込み・実行のみに切り替える
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+struct ExecutableBuffer {
+    ptr: *mut std::ffi::c_void,
+    len: usize,
+}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+impl ExecutableBuffer {
+    fn new(code: &[u8]) -> io::Result<Self> {
+        let len = code.len().max(1);
+        // SAFETY: 新しい無名マッピングを作るだけで、既存のメモリには触らない
+        let ptr = unsafe {
+            sys::mmap(
+                std::ptr::null_mut(),
+                len,
+                sys::PROT_READ | sys::PROT_WRITE,
+                sys::MAP_PRIVATE | sys::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if ptr == sys::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        let buffer = Self { ptr, len };
+
+        // SAFETY: ptr は len バイト以上の書き込み可能な領域を指している
+        unsafe {
+            std::ptr::copy_nonoverlapping(code.as_ptr(), ptr as *mut u8, code.len());
+            if sys::mprotect(ptr, len, sys::PROT_READ | sys::PROT_EXEC) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(buffer)
+    }
+
+    fn call(&self) -> i64 {
+        // SAFETY: バッファの先頭は prologue から始まり ret で戻る main の機械語で、
+        // System V ABIの引数なし関数として呼べる
+        unsafe {
+            let main: extern "C" fn() -> i64 = std::mem::transmute(self.ptr);
+            main()
+        }
+    }
+}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+impl Drop for ExecutableBuffer {
+    fn drop(&mut self) {
+        // SAFETY: new で mmap した領域をそのまま解放する
+        unsafe {
+            sys::munmap(self.ptr, self.len);
+        }
+    }
+}
+
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+struct ExecutableBuffer;
+
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+impl ExecutableBuffer {
+    fn new(_code: &[u8]) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "JIT is only supported on x86-64 Linux",
+        ))
+    }
+
+    fn call(&self) -> i64 {
+        unreachable!()
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64", target_os = "linux"))]
+mod tests {
+    use crate::ast::parser::Parser;
+    use crate::lexer::tokenize;
+
+    use super::*;
+
+    #[test]
+    fn test_run() {
+        struct Test {
+            name: &'static str,
+            input: &'static str,
+            expected: i64,
+        }
+
+        let tests = vec![
+            Test {
+                name: "arithmetic",
+                input: "5+6*7;",
+                expected: 47,
+            },
+            Test {
+                name: "if else",
+                input: "if (1 > 2) return 7; else return 14; return 0;",
+                expected: 14,
+            },
+            Test {
+                name: "for",
+                input: "x = 0; for (i = 0; i < 10; i = i + 1) {x = x + 1;} return x;",
+                expected: 10,
+            },
+            Test {
+                name: "large value",
+                input: "x = 3000000000; return x * 2;",
+                expected: 6000000000,
+            },
+        ];
+
+        for t in tests {
+            for opt_level in [0, 1] {
+                let tokens = tokenize(&mut t.input.chars().peekable()).unwrap();
+                let nodes = Parser::new(tokens.iter()).parse().unwrap();
+                assert_eq!(
+                    run(nodes, opt_level).unwrap(),
+                    t.expected,
+                    "Failed in the {} (-O{})",
+                    t.name,
+                    opt_level
+                );
+            }
+        }
+    }
+}
@@ -1,9 +1,20 @@
 use std::iter::Peekable;
 
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum LexerError {
+    #[error("unterminated string literal")]
+    UnterminatedString,
+}
+
+type Result<T> = std::result::Result<T, LexerError>;
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Token {
     Num(u32),
     Identifier(Box<String>),
+    Str(Box<String>),
     Plus,
     Minus,
     Multiply,
@@ -20,11 +31,15 @@ pub enum Token {
     GreaterThanOrEqual, // ">="
     Assign,             // "="
     Semicolon,          // ";"
+    Comma,              // ","
+    Colon,              // ":"
     Return,
     If,
     Else,
     While,
     For,
+    StaticAssert, // "_Static_assert"
+    Asm,          // "asm", "__asm__"
     EOF,
 }
 
@@ -35,11 +50,11 @@ impl Token {
 
     fn is_almum(c: char) -> bool {
         // alphabet, number, underscore
-        c.is_alphabetic() || c.is_digit(10) || c == '_'
+        c.is_alphabetic() || c.is_ascii_digit() || c == '_'
     }
 }
 
-pub fn tokenize<T: Iterator<Item = char>>(iter: &mut Peekable<T>) -> Vec<Token> {
+pub fn tokenize<T: Iterator<Item = char>>(iter: &mut Peekable<T>) -> Result<Vec<Token>> {
     let mut tokens = Vec::new();
 
     while let Some(c) = iter.peek() {
@@ -48,7 +63,7 @@ pub fn tokenize<T: Iterator<Item = char>>(iter: &mut Peekable<T>) -> Vec<Token>
             continue;
         }
 
-        if c.is_digit(10) {
+        if c.is_ascii_digit() {
             tokens.push(Token::Num(str_to_u(iter).unwrap()));
             continue;
         }
@@ -63,6 +78,9 @@ pub fn tokenize<T: Iterator<Item = char>>(iter: &mut Peekable<T>) -> Vec<Token>
             Some('{') => tokens.push(Token::LeftBrace),
             Some('}') => tokens.push(Token::RightBrace),
             Some(';') => tokens.push(Token::Semicolon),
+            Some(',') => tokens.push(Token::Comma),
+            Some(':') => tokens.push(Token::Colon),
+            Some('"') => tokens.push(Token::Str(Box::new(read_string(iter)?))),
             Some('=') => {
                 if let Some('=') = iter.peek() {
                     iter.next();
@@ -97,7 +115,7 @@ pub fn tokenize<T: Iterator<Item = char>>(iter: &mut Peekable<T>) -> Vec<Token>
                     tokens.push(Token::GreaterThan);
                 }
             }
-            Some(a) if a.is_alphabetic() => {
+            Some(a) if a.is_alphabetic() || a == '_' => {
                 let mut ident = vec![a];
                 while let Some(c) = iter.peek() {
                     if Token::is_almum(*c) {
@@ -115,6 +133,10 @@ pub fn tokenize<T: Iterator<Item = char>>(iter: &mut Peekable<T>) -> Vec<Token>
                     s if s == "else" => tokens.push(Token::Else),
                     s if s == "while" => tokens.push(Token::While),
                     s if s == "for" => tokens.push(Token::For),
+                    s if s == "_Static_assert" => tokens.push(Token::StaticAssert),
+                    s if s == "asm" || s == "__asm__" || s == "__asm" => {
+                        tokens.push(Token::Asm)
+                    }
                     _ => tokens.push(Token::new_identifer(&ident)),
                 }
             }
@@ -124,7 +146,7 @@ pub fn tokenize<T: Iterator<Item = char>>(iter: &mut Peekable<T>) -> Vec<Token>
     }
 
     tokens.push(Token::EOF);
-    tokens
+    Ok(tokens)
 }
 
 pub fn expect_number(tokens: &mut dyn Iterator<Item = &Token>) -> Option<u32> {
@@ -148,14 +170,30 @@ pub fn consume<'a, T: Iterator<Item = &'a Token>>(
     }
 }
 
-fn is_digit<T: Iterator<Item = char>>(iter: &mut Peekable<T>) -> Option<bool> {
-    if let Some(i) = iter.peek() {
-        Some(i.is_digit(10))
-    } else {
-        None
+// 開きの '"' を読んだ後から閉じの '"' までを読み、エスケープシーケンスを解釈した文字列を返す
+fn read_string<T: Iterator<Item = char>>(iter: &mut Peekable<T>) -> Result<String> {
+    let mut s = String::new();
+    loop {
+        match iter.next() {
+            Some('"') => return Ok(s),
+            Some('\\') => match iter.next() {
+                Some('n') => s.push('\n'),
+                Some('t') => s.push('\t'),
+                Some('r') => s.push('\r'),
+                Some('0') => s.push('\0'),
+                Some(c) => s.push(c),
+                None => return Err(LexerError::UnterminatedString),
+            },
+            Some(c) => s.push(c),
+            None => return Err(LexerError::UnterminatedString),
+        }
     }
 }
 
+fn is_digit<T: Iterator<Item = char>>(iter: &mut Peekable<T>) -> Option<bool> {
+    iter.peek().map(|i| i.is_ascii_digit())
+}
+
 fn str_to_u<T: Iterator<Item = char>>(iter: &mut Peekable<T>) -> Option<u32> {
     // 最初の文字が数字でなければNoneを返す
     if !is_digit(iter)? {
@@ -174,6 +212,7 @@ fn str_to_u<T: Iterator<Item = char>>(iter: &mut Peekable<T>) -> Option<u32> {
 }
 
 #[cfg(test)]
+#[allow(clippy::useless_vec)]
 mod tests {
     use super::*;
 
@@ -382,12 +421,71 @@ mod tests {
                     Token::RightBrace,
                     Token::EOF,
                 ],
-            }
+            },
+            Test {
+                name: "static assert",
+                input: "_Static_assert(1, \"a\\\"b\\n\"); _x",
+                expected: vec![
+                    Token::StaticAssert,
+                    Token::LeftParen,
+                    Token::Num(1),
+                    Token::Comma,
+                    Token::Str(Box::new("a\"b\n".to_string())),
+                    Token::RightParen,
+                    Token::Semicolon,
+                    Token::new_identifer("_x"),
+                    Token::EOF,
+                ],
+            },
+            Test {
+                name: "asm",
+                input: "asm(\"nop\" : : : \"rax\"); __asm__",
+                expected: vec![
+                    Token::Asm,
+                    Token::LeftParen,
+                    Token::Str(Box::new("nop".to_string())),
+                    Token::Colon,
+                    Token::Colon,
+                    Token::Colon,
+                    Token::Str(Box::new("rax".to_string())),
+                    Token::RightParen,
+                    Token::Semicolon,
+                    Token::Asm,
+                    Token::EOF,
+                ],
+            },
+        ];
+
+        for t in tests {
+            let mut c = t.input.chars().peekable();
+            assert_eq!(tokenize(&mut c), Ok(t.expected), "Faild in the {}", t.name,);
+        }
+    }
+
+    #[test]
+    fn test_tokenize_error() {
+        struct Test<'a> {
+            name: &'a str,
+            input: &'a str,
+            expected: LexerError,
+        }
+
+        let tests = vec![
+            Test {
+                name: "unterminated string",
+                input: "_Static_assert(1, \"x",
+                expected: LexerError::UnterminatedString,
+            },
+            Test {
+                name: "unterminated escape",
+                input: "\"x\\",
+                expected: LexerError::UnterminatedString,
+            },
         ];
 
         for t in tests {
             let mut c = t.input.chars().peekable();
-            assert_eq!(tokenize(&mut c), t.expected, "Faild in the {}", t.name,);
+            assert_eq!(tokenize(&mut c), Err(t.expected), "Failed in the {}", t.name);
         }
     }
 
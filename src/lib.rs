@@ -1,3 +1,10 @@
 pub mod ast;
+pub mod bytecode;
 pub mod gen;
+pub mod interp;
+pub mod ir;
+pub mod jit;
 pub mod lexer;
+pub mod linker;
+pub mod preprocessor;
+pub mod vm;
@@ -0,0 +1,409 @@
+use std::collections::HashMap;
+
+use thiserror::Error;
+
+use crate::gen::elf::{align, put_elf_header, put_u32, put_u64, ObjectFile, Relocation, RelocationKind, Symbol};
+use crate::gen::encode::encode;
+use crate::gen::x86_64::{Inst, Operand, Reg};
+
+// 実行ファイルを読み込むアドレス
+const BASE_ADDRESS: u64 = 0x400000;
+const PAGE_SIZE: u64 = 0x1000;
+const PHDR_SIZE: usize = 56;
+
+const ET_EXEC: u16 = 2;
+const PT_LOAD: u32 = 1;
+const PT_GNU_STACK: u32 = 0x6474e551;
+const PF_X: u32 = 0x1;
+const PF_W: u32 = 0x2;
+const PF_R: u32 = 0x4;
+
+const SHT_SYMTAB: u32 = 2;
+const SHT_RELA: u32 = 4;
+const SHN_UNDEF: u16 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_SECTION: u8 = 3;
+const R_X86_64_PC32: u32 = 2;
+const R_X86_64_PLT32: u32 = 4;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum LinkerError {
+    #[error("invalid object file: {0}")]
+    InvalidObject(String),
+    #[error("unsupported relocation type: {0}")]
+    UnsupportedRelocation(u32),
+    #[error("undefined symbol: {0}")]
+    UndefinedSymbol(String),
+    #[error("duplicate symbol: {0}")]
+    DuplicateSymbol(String),
+    #[error("relocation overflow against {0}")]
+    RelocationOverflow(String),
+}
+
+type Result<T> = std::result::Result<T, LinkerError>;
+
+// _start から main を呼び、その戻り値で exit する
+pub fn startup_object() -> ObjectFile {
+    let insts = [
+        Inst::Call("main".to_string()),
+        Inst::Mov(Operand::Reg(Reg::Rdi), Operand::Reg(Reg::Rax)),
+        Inst::Mov(Operand::Reg(Reg::Rax), Operand::Imm(60)), // exit
+        Inst::Syscall,
+    ];
+    let assembled = encode(&insts).unwrap();
+    ObjectFile {
+        symbols: vec![Symbol {
+            name: "_start".to_string(),
+            offset: 0,
+            size: assembled.code.len() as u64,
+            global: true,
+            defined: true,
+        }],
+        text: assembled.code,
+        relocations: assembled.relocations,
+    }
+}
+
+// オブジェクトファイルをリンクして静的な実行ファイルを作る
+// 全オブジェクトの.textを1つのPT_LOADセグメントに並べ、エントリポイントは _start
+pub fn link(objects: &[ObjectFile]) -> Result<Vec<u8>> {
+    let header_size = 64 + 2 * PHDR_SIZE;
+
+    // 各オブジェクトの.textの配置を決める
+    let mut text = Vec::new();
+    let mut bases = Vec::new();
+    for object in objects {
+        text.resize(align(header_size + text.len(), 16) - header_size, 0);
+        bases.push(BASE_ADDRESS + (header_size + text.len()) as u64);
+        text.extend_from_slice(&object.text);
+    }
+
+    let mut globals = HashMap::new();
+    for (object, base) in objects.iter().zip(&bases) {
+        for s in object.symbols.iter().filter(|s| s.global && s.defined) {
+            if globals.insert(s.name.clone(), base + s.offset).is_some() {
+                return Err(LinkerError::DuplicateSymbol(s.name.clone()));
+            }
+        }
+    }
+    let resolve = |object: &ObjectFile, base: u64, name: &str| -> Result<u64> {
+        // ローカルシンボルはそのオブジェクトの中だけで探す
+        if let Some(s) = object.symbols.iter().find(|s| !s.global && s.defined && s.name == name) {
+            return Ok(base + s.offset);
+        }
+        globals
+            .get(name)
+            .copied()
+            .ok_or_else(|| LinkerError::UndefinedSymbol(name.to_string()))
+    };
+
+    for (object, base) in objects.iter().zip(&bases) {
+        for r in &object.relocations {
+            // 書き換える4バイトがそのオブジェクトの.textに収まっていなければならない
+            let end = r.offset.checked_add(4);
+            if end.is_none_or(|end| end > object.text.len() as u64) {
+                return Err(LinkerError::InvalidObject(format!(
+                    "relocation offset out of .text: {:#x}",
+                    r.offset
+                )));
+            }
+            let target = resolve(object, *base, &r.symbol)?;
+            let place = base + r.offset;
+            // 静的リンクではPLTを経由しないので、PLT32もPC32と同じ計算になる
+            let value = target as i64 + r.addend - place as i64;
+            let value = i32::try_from(value)
+                .map_err(|_| LinkerError::RelocationOverflow(r.symbol.clone()))?;
+            let pos = (place - BASE_ADDRESS) as usize - header_size;
+            text[pos..pos + 4].copy_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    let entry = *globals
+        .get("_start")
+        .ok_or_else(|| LinkerError::UndefinedSymbol("_start".to_string()))?;
+    let file_size = (header_size + text.len()) as u64;
+
+    let mut out = Vec::new();
+    put_elf_header(&mut out, ET_EXEC, entry, 64, 0, 2, 0);
+    // ヘッダごと読み込む実行可能セグメント
+    put_program_header(&mut out, PT_LOAD, PF_R | PF_X, 0, BASE_ADDRESS, file_size, PAGE_SIZE);
+    // スタックを実行不可にする
+    put_program_header(&mut out, PT_GNU_STACK, PF_R | PF_W, 0, 0, 0, 16);
+    out.extend_from_slice(&text);
+    Ok(out)
+}
+
+fn put_program_header(out: &mut Vec<u8>, typ: u32, flags: u32, offset: u64, vaddr: u64, size: u64, align: u64) {
+    put_u32(out, typ);
+    put_u32(out, flags);
+    put_u64(out, offset);
+    put_u64(out, vaddr);
+    put_u64(out, vaddr);
+    put_u64(out, size);
+    put_u64(out, size);
+    put_u64(out, align);
+}
+
+// ELF64の再配置可能オブジェクトから .text とそのシンボル、再配置を読み出す
+pub fn read_object(bytes: &[u8]) -> Result<ObjectFile> {
+    let invalid = |message: &str| LinkerError::InvalidObject(message.to_string());
+    if bytes.len() < 64 || bytes[..4] != [0x7f, b'E', b'L', b'F'] {
+        return Err(invalid("not an ELF file"));
+    }
+    if bytes[4] != 2 || bytes[5] != 1 || u16_at(bytes, 16)? != 1 || u16_at(bytes, 18)? != 62 {
+        return Err(invalid("not an x86-64 relocatable object"));
+    }
+
+    let shoff = u64_at(bytes, 0x28)? as usize;
+    let shnum = u16_at(bytes, 0x3c)? as usize;
+    let shstrndx = u16_at(bytes, 0x3e)? as usize;
+    slice(bytes, shoff, shnum * 64)?;
+    let sections = (0..shnum)
+        .map(|i| SectionHeader::read(bytes, shoff + i * 64))
+        .collect::<Result<Vec<_>>>()?;
+    let shstrtab = sections.get(shstrndx).ok_or_else(|| invalid("no section name table"))?;
+    slice(bytes, shstrtab.offset, shstrtab.size)?;
+    let section_name = |s: &SectionHeader| c_str(bytes, shstrtab.offset + s.name as usize);
+
+    let text_index = sections
+        .iter()
+        .position(|s| section_name(s).is_ok_and(|n| n == ".text"))
+        .ok_or_else(|| invalid("no .text section"))?;
+    let text = slice(bytes, sections[text_index].offset, sections[text_index].size)?.to_vec();
+
+    let mut symbols = Vec::new();
+    let mut names = Vec::new();
+    if let Some(symtab) = sections.iter().find(|s| s.typ == SHT_SYMTAB) {
+        let strtab = sections.get(symtab.link as usize).ok_or_else(|| invalid("no string table"))?;
+        slice(bytes, symtab.offset, symtab.size)?;
+        slice(bytes, strtab.offset, strtab.size)?;
+        for i in 0..symtab.size / 24 {
+            let pos = symtab.offset + i * 24;
+            let name = c_str(bytes, strtab.offset + u32_at(bytes, pos)? as usize)?;
+            let info = *bytes.get(pos + 4).ok_or_else(|| invalid("truncated symbol"))?;
+            let shndx = u16_at(bytes, pos + 6)?;
+            let value = u64_at(bytes, pos + 8)?;
+            let size = u64_at(bytes, pos + 16)?;
+
+            // .textのセクションシンボルは .text という名前のローカルシンボルとして扱う
+            let name = if info & 0xf == STT_SECTION && shndx as usize == text_index {
+                ".text".to_string()
+            } else {
+                name
+            };
+            names.push(name.clone());
+            if i == 0 || (shndx != SHN_UNDEF && shndx as usize != text_index) {
+                continue;
+            }
+            symbols.push(Symbol {
+                name,
+                offset: value,
+                size,
+                global: info >> 4 == STB_GLOBAL,
+                defined: shndx != SHN_UNDEF,
+            });
+        }
+    }
+
+    let mut relocations = Vec::new();
+    for rela in sections
+        .iter()
+        .filter(|s| s.typ == SHT_RELA && s.info as usize == text_index)
+    {
+        slice(bytes, rela.offset, rela.size)?;
+        for i in 0..rela.size / 24 {
+            let pos = rela.offset + i * 24;
+            let info = u64_at(bytes, pos + 8)?;
+            let kind = match info as u32 {
+                R_X86_64_PC32 => RelocationKind::Pc32,
+                R_X86_64_PLT32 => RelocationKind::Plt32,
+                other => return Err(LinkerError::UnsupportedRelocation(other)),
+            };
+            let symbol = names
+                .get((info >> 32) as usize)
+                .ok_or_else(|| invalid("relocation against unknown symbol"))?;
+            relocations.push(Relocation {
+                offset: u64_at(bytes, pos)?,
+                symbol: symbol.clone(),
+                kind,
+                addend: u64_at(bytes, pos + 16)? as i64,
+            });
+        }
+    }
+
+    Ok(ObjectFile {
+        text,
+        symbols,
+        relocations,
+    })
+}
+
+struct SectionHeader {
+    name: u32,
+    typ: u32,
+    offset: usize,
+    size: usize,
+    link: u32,
+    info: u32,
+}
+
+impl SectionHeader {
+    fn read(bytes: &[u8], pos: usize) -> Result<Self> {
+        Ok(SectionHeader {
+            name: u32_at(bytes, pos)?,
+            typ: u32_at(bytes, pos + 4)?,
+            offset: u64_at(bytes, pos + 24)? as usize,
+            size: u64_at(bytes, pos + 32)? as usize,
+            link: u32_at(bytes, pos + 40)?,
+            info: u32_at(bytes, pos + 44)?,
+        })
+    }
+}
+
+fn slice(bytes: &[u8], pos: usize, len: usize) -> Result<&[u8]> {
+    pos.checked_add(len)
+        .and_then(|end| bytes.get(pos..end))
+        .ok_or_else(|| LinkerError::InvalidObject("truncated file".to_string()))
+}
+
+fn u16_at(bytes: &[u8], pos: usize) -> Result<u16> {
+    Ok(u16::from_le_bytes(slice(bytes, pos, 2)?.try_into().unwrap()))
+}
+
+fn u32_at(bytes: &[u8], pos: usize) -> Result<u32> {
+    Ok(u32::from_le_bytes(slice(bytes, pos, 4)?.try_into().unwrap()))
+}
+
+fn u64_at(bytes: &[u8], pos: usize) -> Result<u64> {
+    Ok(u64::from_le_bytes(slice(bytes, pos, 8)?.try_into().unwrap()))
+}
+
+fn c_str(bytes: &[u8], pos: usize) -> Result<String> {
+    let rest = bytes
+        .get(pos..)
+        .ok_or_else(|| LinkerError::InvalidObject("truncated string table".to_string()))?;
+    let end = rest.iter().position(|b| *b == 0).unwrap_or(rest.len());
+    Ok(String::from_utf8_lossy(&rest[..end]).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn main_object(code: Vec<u8>) -> ObjectFile {
+        ObjectFile {
+            symbols: vec![Symbol {
+                name: "main".to_string(),
+                offset: 0,
+                size: code.len() as u64,
+                global: true,
+                defined: true,
+            }],
+            text: code,
+            relocations: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_read_object() {
+        let object = startup_object();
+        let mut bytes = Vec::new();
+        object.write(&mut bytes).unwrap();
+
+        let read = read_object(&bytes).unwrap();
+        assert_eq!(read.text, object.text);
+        assert_eq!(read.relocations, object.relocations);
+        assert_eq!(
+            read.symbols,
+            vec![
+                Symbol {
+                    name: ".text".to_string(),
+                    offset: 0,
+                    size: 0,
+                    global: false,
+                    defined: true,
+                },
+                object.symbols[0].clone(),
+                Symbol {
+                    name: "main".to_string(),
+                    offset: 0,
+                    size: 0,
+                    global: true,
+                    defined: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_link() {
+        // mov eax, 42; ret
+        let main = main_object(vec![0xb8, 42, 0, 0, 0, 0xc3]);
+        let exe = link(&[startup_object(), main]).unwrap();
+
+        let header_size = 64 + 2 * PHDR_SIZE;
+        let entry = u64_at(&exe, 0x18).unwrap();
+        assert_eq!(entry, BASE_ADDRESS + header_size as u64);
+
+        // call main の飛び先が main の先頭を指している
+        let call = header_size;
+        assert_eq!(exe[call], 0xe8);
+        let rel = i32::from_le_bytes(exe[call + 1..call + 5].try_into().unwrap()) as i64;
+        let main_pos = align(header_size + startup_object().text.len(), 16);
+        assert_eq!(call as i64 + 5 + rel, main_pos as i64);
+        assert_eq!(exe[main_pos..], [0xb8, 42, 0, 0, 0, 0xc3]);
+    }
+
+    #[test]
+    fn test_link_error() {
+        assert_eq!(
+            link(&[startup_object()]),
+            Err(LinkerError::UndefinedSymbol("main".to_string()))
+        );
+        assert_eq!(
+            link(&[startup_object(), main_object(vec![0xc3]), main_object(vec![0xc3])]),
+            Err(LinkerError::DuplicateSymbol("main".to_string()))
+        );
+
+        // 再配置の位置が.textの外を指している
+        let mut main = main_object(vec![0xc3]);
+        main.relocations.push(Relocation {
+            offset: u64::MAX - 1,
+            symbol: "main".to_string(),
+            kind: RelocationKind::Pc32,
+            addend: -4,
+        });
+        assert_eq!(
+            link(&[startup_object(), main]),
+            Err(LinkerError::InvalidObject("relocation offset out of .text: 0xfffffffffffffffe".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_read_object_error() {
+        let mut bytes = Vec::new();
+        startup_object().write(&mut bytes).unwrap();
+
+        // .textの大きさを壊して、位置 + 大きさがあふれるようにする
+        let shoff = u64_at(&bytes, 0x28).unwrap() as usize;
+        let shnum = u16_at(&bytes, 0x3c).unwrap() as usize;
+        let shstrtab = SectionHeader::read(&bytes, shoff + u16_at(&bytes, 0x3e).unwrap() as usize * 64).unwrap();
+        let text = (0..shnum)
+            .map(|i| shoff + i * 64)
+            .find(|pos| {
+                let s = SectionHeader::read(&bytes, *pos).unwrap();
+                c_str(&bytes, shstrtab.offset + s.name as usize).unwrap() == ".text"
+            })
+            .unwrap();
+        bytes[text + 32..text + 40].copy_from_slice(&u64::MAX.to_le_bytes());
+
+        assert_eq!(
+            read_object(&bytes),
+            Err(LinkerError::InvalidObject("truncated file".to_string()))
+        );
+        assert_eq!(
+            read_object(&bytes[..100]),
+            Err(LinkerError::InvalidObject("truncated file".to_string()))
+        );
+    }
+}
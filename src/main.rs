@@ -1,29 +1,326 @@
 use std::env;
-use std::io;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process;
 
+use rust9cc::bytecode::{self, Program};
+use rust9cc::gen::aarch64::Aarch64CodeGen;
+use rust9cc::gen::c::{CSourceCodeGen, Parens};
+use rust9cc::gen::elf::ElfCodeGen;
+use rust9cc::gen::llvm::LlvmCodeGen;
+use rust9cc::gen::riscv64::Riscv64CodeGen;
+use rust9cc::gen::wasm::{WasmCodeGen, WatCodeGen};
+use rust9cc::gen::x86_64::Syntax;
 use rust9cc::gen::CodeGen;
-use rust9cc::{ast, gen, lexer};
+use rust9cc::interp::Interpreter;
+use rust9cc::jit;
+use rust9cc::preprocessor::Preprocessor;
+use rust9cc::vm::Vm;
+use rust9cc::{ast, gen, ir, lexer, linker};
 
-fn main() -> io::Result<()> {
-    let args: Vec<String> = env::args().collect();
+#[derive(PartialEq)]
+enum Target {
+    X86_64,
+    Aarch64,
+    Riscv64,
+    Wasm32,
+}
+
+impl Target {
+    fn parse(name: &str) -> Self {
+        match name {
+            "x86_64" | "x86-64" => Target::X86_64,
+            "aarch64" | "arm64" => Target::Aarch64,
+            "riscv64" => Target::Riscv64,
+            "wasm32" | "wasm" => Target::Wasm32,
+            _ => panic!("対応していないターゲットです: {}", name),
+        }
+    }
+}
+
+// 何を出力するか
+#[derive(PartialEq)]
+enum Emit {
+    Asm,
+    Llvm,
+    C(Parens),
+    Bytecode,
+    Ir,
+}
+
+impl Emit {
+    fn parse(name: &str) -> Self {
+        match name {
+            "asm" => Emit::Asm,
+            "llvm" | "llvm-ir" => Emit::Llvm,
+            "c" => Emit::C(Parens::Minimal),
+            "c-full" => Emit::C(Parens::Full),
+            "bytecode" => Emit::Bytecode,
+            "ir" => Emit::Ir,
+            _ => panic!("対応していない出力形式です: {}", name),
+        }
+    }
+}
+
+struct Options {
+    inputs: Vec<String>,
+    output: Option<PathBuf>,
+    include_paths: Vec<PathBuf>,
+    preprocess_only: bool,
+    object: bool,
+    static_link: bool,
+    target: Target,
+    syntax: Syntax,
+    opt_level: u8, // x86-64のアセンブリ、オブジェクトファイルとJITだけが使う
+    emit: Emit,
+    interpret: bool,
+    run: bool,
+    vm: bool,
+}
+
+impl Options {
+    fn parse(args: impl Iterator<Item = String>) -> Self {
+        let mut inputs = Vec::new();
+        let mut output = None;
+        let mut include_paths = Vec::new();
+        let mut preprocess_only = false;
+        let mut object = false;
+        let mut static_link = false;
+        let mut target = Target::X86_64;
+        let mut syntax = Syntax::Intel;
+        let mut opt_level = 0;
+        let mut emit = Emit::Asm;
+        let mut interpret = false;
+        let mut run = false;
+        let mut vm = false;
+
+        // rust9cc run <input> でその場で実行する
+        // x86-64ではJITで機械語を直接実行し、それ以外のホストや --vm ではバイトコードにしてVMで実行する
+        let mut args = args.skip(1).peekable();
+        if args.peek().map(String::as_str) == Some("run") {
+            args.next();
+            run = true;
+        }
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-E" => preprocess_only = true,
+                "-c" => object = true,
+                "-static" => static_link = true,
+                "--interpret" => interpret = true,
+                "--vm" => vm = true,
+                "-masm=intel" => syntax = Syntax::Intel,
+                "-masm=att" => syntax = Syntax::Att,
+                "-O" => opt_level = 1,
+                "-o" => {
+                    let path = args.next().expect("-o の後に出力ファイルが必要です");
+                    output = Some(PathBuf::from(path));
+                }
+                "-I" => {
+                    let dir = args.next().expect("-I の後にディレクトリが必要です");
+                    include_paths.push(PathBuf::from(dir));
+                }
+                "--target" => {
+                    let name = args.next().expect("--target の後にターゲットが必要です");
+                    target = Target::parse(&name);
+                }
+                s if s.starts_with("--target=") => target = Target::parse(&s[9..]),
+                s if s.starts_with("--emit=") => emit = Emit::parse(&s[7..]),
+                s if s.starts_with("-I") => include_paths.push(PathBuf::from(&s[2..])),
+                s if s.starts_with("-O") => {
+                    opt_level = s[2..]
+                        .parse()
+                        .unwrap_or_else(|_| panic!("最適化レベルが不正です: {}", s))
+                }
+                _ => inputs.push(arg),
+            }
+        }
+
+        // 複数の入力を受け付けるのはリンクするときだけ
+        if inputs.is_empty() || (inputs.len() > 1 && !static_link) {
+            panic!("引数の数が正しくありません");
+        }
+        // オブジェクトファイルの出力はx86-64とwasm32 (.wasm)、リンクはx86-64のみ
+        if object && !matches!(target, Target::X86_64 | Target::Wasm32) {
+            panic!("-c はx86-64とwasm32でのみ使えます");
+        }
+        if static_link && target != Target::X86_64 {
+            panic!("-static はx86-64でのみ使えます");
+        }
+        // 1つの入力全体が main になるので、コンパイルするソースは1つまで
+        if static_link && inputs.iter().filter(|input| !input.ends_with(".o")).count() > 1 {
+            panic!("-static でコンパイルできるソースファイルは1つだけです");
+        }
+        if (object || static_link) && emit != Emit::Asm {
+            panic!("--emit と -c, -static は同時に使えません");
+        }
+        if interpret && (object || static_link || emit != Emit::Asm) {
+            panic!("--interpret と -c, -static, --emit は同時に使えません");
+        }
+        if run && (object || static_link || interpret || emit != Emit::Asm) {
+            panic!("run と -c, -static, --interpret, --emit は同時に使えません");
+        }
+        // 最適化はx86-64のIRからの命令選択でだけ効くので、無視される組み合わせは受け付けない
+        if opt_level > 0
+            && !preprocess_only
+            && (target != Target::X86_64 || emit != Emit::Asm || interpret || vm)
+        {
+            panic!("-O はx86-64のアセンブリ、オブジェクトファイルとJITでのみ使えます");
+        }
+
+        Options {
+            inputs,
+            output,
+            include_paths,
+            preprocess_only,
+            object,
+            static_link,
+            target,
+            syntax,
+            opt_level,
+            emit,
+            interpret,
+            run,
+            vm,
+        }
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let options = Options::parse(env::args());
+
+    if options.static_link {
+        // .oで終わる入力はオブジェクトファイルとして読み、それ以外はコンパイルしてからリンクする
+        let mut objects = vec![linker::startup_object()];
+        for input in &options.inputs {
+            let bytes = if input.ends_with(".o") {
+                fs::read(input)?
+            } else {
+                let mut bytes = Vec::new();
+                compile(input, &options, &mut bytes, true)?;
+                bytes
+            };
+            objects.push(linker::read_object(&bytes)?);
+        }
+        let exe = linker::link(&objects)?;
+        output(&options)?.write_all(&exe)?;
 
-    if args.len() != 2 {
-        panic!("引数の数が正しくありません");
+        #[cfg(unix)]
+        if let Some(path) = &options.output {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(path, fs::Permissions::from_mode(0o755))?;
+        }
+        return Ok(());
     }
-    let mut c = args[1].chars().peekable();
+
+    // アセンブラを通さずにその場で実行し、戻り値を終了コードにする
+    if options.interpret && !options.preprocess_only {
+        let nodes = parse(&preprocess(&options.inputs[0], &options)?)?;
+        let value = Interpreter::new().run(&nodes)?;
+        process::exit(value as i32);
+    }
+
+    if options.run {
+        let input = &options.inputs[0];
+        // VMで実行するかはホストや入力によって決まるので、-O が効かないことはここで知らせる
+        let warn_opt = || {
+            if options.opt_level > 0 {
+                eprintln!("warning: -O is ignored when running on the VM");
+            }
+        };
+        // コンパイル済みのバイトコードのファイルならそのまま読み込む
+        let value = match fs::read(input) {
+            Ok(bytes) if bytecode::is_bytecode(&bytes) => {
+                warn_opt();
+                Vm::new(&Program::decode(&bytes)?).run()?
+            }
+            _ => {
+                let nodes = parse(&preprocess(input, &options)?)?;
+                if jit::SUPPORTED && !options.vm {
+                    jit::run(nodes, options.opt_level)?
+                } else {
+                    warn_opt();
+                    Vm::new(&bytecode::compile(&nodes)?).run()?
+                }
+            }
+        };
+        process::exit(value as i32);
+    }
+
+    // 途中で失敗しても -o のファイルを壊さないように、全部生成してから書き出す
+    let mut bytes = Vec::new();
+    compile(&options.inputs[0], &options, &mut bytes, options.object)?;
+    output(&options)?.write_all(&bytes)?;
+    Ok(())
+}
+
+// -o がなければ標準出力に書き出す
+// ファイルを作ると中身が消えるので、出力する内容をすべて作り終えてから呼ぶ
+fn output(options: &Options) -> io::Result<Box<dyn Write>> {
+    Ok(match &options.output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout().lock()),
+    })
+}
+
+// .cで終わる引数はファイルとして読み、それ以外はソースコードそのものとして扱う
+fn preprocess(input: &str, options: &Options) -> anyhow::Result<String> {
+    let mut preprocessor = Preprocessor::new(options.include_paths.clone());
+    let src = if input.ends_with(".c") {
+        preprocessor.preprocess_file(&PathBuf::from(input))?
+    } else {
+        preprocessor.preprocess(input, "<command line>")?
+    };
+    Ok(src)
+}
+
+fn parse(src: &str) -> anyhow::Result<ast::node::Nodes> {
+    let mut c = src.chars().peekable();
 
     // tokenize
-    let tokens = lexer::tokenize(&mut c);
+    let tokens = lexer::tokenize(&mut c)?;
     let mut token_iter = tokens.iter();
 
     // ast
     let mut parser = ast::parser::Parser::new(&mut token_iter);
-    let nodes = parser.parse().unwrap();
+    Ok(parser.parse()?)
+}
+
+fn compile(input: &str, options: &Options, out: &mut dyn Write, object: bool) -> anyhow::Result<()> {
+    let src = preprocess(input, options)?;
+    if options.preprocess_only {
+        write!(out, "{}", src)?;
+        return Ok(());
+    }
+    let nodes = parse(&src)?;
+
+    if options.emit == Emit::Bytecode {
+        out.write_all(&bytecode::compile(&nodes)?.encode())?;
+        return Ok(());
+    }
+    if options.emit == Emit::Ir {
+        write!(out, "{}", ir::lower(&nodes))?;
+        return Ok(());
+    }
 
-    // gen assembly code to stdout
-    let stdout = io::stdout();
-    let mut stdout = stdout.lock();
-    let mut gen = gen::AsmCodeGen::new(&mut stdout);
+    // gen assembly code or an object file (-c)
+    let mut gen: Box<dyn CodeGen<_>> = match options.emit {
+        Emit::Llvm => Box::new(LlvmCodeGen::new(out)),
+        Emit::C(parens) => Box::new(CSourceCodeGen::new(out, parens)),
+        Emit::Bytecode | Emit::Ir => unreachable!("バイトコードとIRは上で出力済み"),
+        Emit::Asm => match options.target {
+            Target::X86_64 if object => Box::new(ElfCodeGen::with_opt_level(out, options.opt_level)),
+            Target::X86_64 => Box::new(gen::AsmCodeGen::with_options(
+                out,
+                options.syntax,
+                options.opt_level,
+            )),
+            Target::Aarch64 => Box::new(Aarch64CodeGen::new(out)),
+            Target::Riscv64 => Box::new(Riscv64CodeGen::new(out)),
+            Target::Wasm32 if object => Box::new(WasmCodeGen::new(out)),
+            Target::Wasm32 => Box::new(WatCodeGen::new(out)),
+        },
+    };
 
     gen.prefix()?;
     gen.prologue()?;
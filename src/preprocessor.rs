@@ -0,0 +1,1045 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+mod expr;
+
+const MAX_INCLUDE_DEPTH: usize = 200;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum PreprocessorError {
+    #[error("#error {0}")]
+    UserError(String),
+    #[error("include file not found: {0}")]
+    IncludeNotFound(String),
+    #[error("#include nested too deeply")]
+    IncludeTooDeep,
+    #[error("cannot read {path}: {message}")]
+    Io { path: String, message: String },
+    #[error("invalid directive: #{0}")]
+    InvalidDirective(String),
+    #[error("#{0} without #if")]
+    UnmatchedConditional(String),
+    #[error("unterminated conditional directive")]
+    UnterminatedConditional,
+    #[error("unterminated comment")]
+    UnterminatedComment,
+    #[error("invalid macro definition")]
+    InvalidMacroDefinition,
+    #[error("wrong number of arguments for macro {0}")]
+    WrongNumberOfArguments(String),
+    #[error("unterminated argument list for macro {0}")]
+    UnterminatedArguments(String),
+    #[error("invalid constant expression")]
+    InvalidExpression,
+    #[error("division by zero in constant expression")]
+    DivisionByZero,
+}
+
+type Result<T> = std::result::Result<T, PreprocessorError>;
+
+// プリプロセッサが扱うトークン
+// 空白は1つのSpaceにまとめて保持し、-Eの出力で元の見た目を大きく崩さないようにする
+#[derive(Debug, PartialEq, Clone)]
+enum PpToken {
+    Ident(String),
+    Num(String),
+    Str(String), // "..." と '...' (引用符を含む)
+    Punct(String),
+    Space,
+}
+
+impl PpToken {
+    fn punct(s: &str) -> PpToken {
+        PpToken::Punct(s.to_string())
+    }
+
+    fn text(&self) -> &str {
+        match self {
+            PpToken::Ident(s) | PpToken::Num(s) | PpToken::Str(s) | PpToken::Punct(s) => s,
+            PpToken::Space => " ",
+        }
+    }
+}
+
+// マクロ展開中のトークン
+// hidesetに含まれるマクロはこのトークンからは再展開しない
+#[derive(Debug, Clone)]
+struct Tok {
+    token: PpToken,
+    hideset: HashSet<String>,
+}
+
+#[derive(Debug, Clone)]
+enum Macro {
+    Object(Vec<PpToken>),
+    Function {
+        params: Vec<String>, // 可変長引数の場合は末尾が __VA_ARGS__
+        variadic: bool,
+        body: Vec<PpToken>,
+    },
+}
+
+// #if のネスト1段分の状態
+struct Cond {
+    active: bool, // 今いるグループを出力するか
+    taken: bool,  // このネストで既にいずれかのグループを選んだか
+    parent_active: bool,
+    in_else: bool,
+}
+
+pub struct Preprocessor {
+    include_paths: Vec<PathBuf>,
+    macros: HashMap<String, Macro>,
+    pragma_once: HashSet<PathBuf>,
+    path: Option<PathBuf>,
+    file: String,
+    line: usize,
+    depth: usize,
+}
+
+impl Preprocessor {
+    pub fn new(include_paths: Vec<PathBuf>) -> Self {
+        let mut macros = HashMap::new();
+        macros.insert(
+            "__STDC__".to_string(),
+            Macro::Object(vec![PpToken::Num("1".to_string())]),
+        );
+        Preprocessor {
+            include_paths,
+            macros,
+            pragma_once: HashSet::new(),
+            path: None,
+            file: String::new(),
+            line: 0,
+            depth: 0,
+        }
+    }
+
+    pub fn preprocess_file(&mut self, path: &Path) -> Result<String> {
+        if self.depth >= MAX_INCLUDE_DEPTH {
+            return Err(PreprocessorError::IncludeTooDeep);
+        }
+        let io_error = |e: std::io::Error| PreprocessorError::Io {
+            path: path.display().to_string(),
+            message: e.to_string(),
+        };
+        if self.pragma_once.contains(&path.canonicalize().map_err(io_error)?) {
+            return Ok(String::new());
+        }
+        let src = fs::read_to_string(path).map_err(io_error)?;
+
+        let parent_path = self.path.replace(path.to_path_buf());
+        self.depth += 1;
+        let result = self.preprocess(&src, &path.display().to_string());
+        self.depth -= 1;
+        self.path = parent_path;
+        result
+    }
+
+    pub fn preprocess(&mut self, src: &str, file: &str) -> Result<String> {
+        let parent_file = std::mem::replace(&mut self.file, file.to_string());
+        let parent_line = self.line;
+        let result = self.preprocess_lines(src);
+        self.file = parent_file;
+        self.line = parent_line;
+        result
+    }
+
+    fn preprocess_lines(&mut self, src: &str) -> Result<String> {
+        let src = remove_comments(src)?;
+        let lines = logical_lines(&src);
+        let mut conds: Vec<Cond> = Vec::new();
+        let mut out = String::new();
+
+        let mut i = 0;
+        while i < lines.len() {
+            let (start, text, mut count) = lines[i].clone();
+            self.line = start;
+            let active = conds.last().is_none_or(|c| c.active);
+
+            if let Some(directive) = text.trim_start().strip_prefix('#') {
+                out.push_str(&self.directive(directive, &mut conds)?);
+            } else if active {
+                // 関数形式マクロの引数が次の行に続いている場合は行をつなげて展開し直す
+                let mut text = text;
+                loop {
+                    match self.expand(pp_tokenize(&text)) {
+                        Err(PreprocessorError::UnterminatedArguments(_))
+                            if i + 1 < lines.len()
+                                && !lines[i + 1].1.trim_start().starts_with('#') =>
+                        {
+                            i += 1;
+                            text.push(' ');
+                            text.push_str(&lines[i].1);
+                            count += lines[i].2;
+                        }
+                        result => {
+                            out.push_str(&join(&result?));
+                            break;
+                        }
+                    }
+                }
+            }
+
+            for _ in 0..count {
+                out.push('\n');
+            }
+            i += 1;
+        }
+
+        if !conds.is_empty() {
+            return Err(PreprocessorError::UnterminatedConditional);
+        }
+        Ok(out)
+    }
+
+    // ディレクティブを処理する
+    // #include の場合は取り込んだファイルの展開結果を返す
+    fn directive(&mut self, line: &str, conds: &mut Vec<Cond>) -> Result<String> {
+        let tokens = trim(&pp_tokenize(line));
+        let active = conds.last().is_none_or(|c| c.active);
+        let (name, rest) = match tokens.first() {
+            Some(PpToken::Ident(name)) => (name.as_str(), trim(&tokens[1..])),
+            // 空のディレクティブ
+            None => return Ok(String::new()),
+            // # 1 "file" のような行も読み飛ばしているグループの中では無視する
+            Some(_) if !active => return Ok(String::new()),
+            Some(t) => return Err(PreprocessorError::InvalidDirective(t.text().to_string())),
+        };
+
+        match name {
+            "if" | "ifdef" | "ifndef" => {
+                let taken = active
+                    && match name {
+                        "if" => self.eval_condition(&rest)?,
+                        "ifdef" => self.macros.contains_key(macro_name(&rest)?),
+                        _ => !self.macros.contains_key(macro_name(&rest)?),
+                    };
+                conds.push(Cond {
+                    active: taken,
+                    taken,
+                    parent_active: active,
+                    in_else: false,
+                });
+                return Ok(String::new());
+            }
+            "elif" => {
+                let cond = match conds.last() {
+                    Some(c) if !c.in_else => c,
+                    _ => return Err(PreprocessorError::UnmatchedConditional(name.to_string())),
+                };
+                let active = cond.parent_active && !cond.taken && self.eval_condition(&rest)?;
+                let cond = conds.last_mut().unwrap();
+                cond.active = active;
+                cond.taken |= active;
+                return Ok(String::new());
+            }
+            "else" => {
+                let cond = match conds.last_mut() {
+                    Some(c) if !c.in_else => c,
+                    _ => return Err(PreprocessorError::UnmatchedConditional(name.to_string())),
+                };
+                cond.active = cond.parent_active && !cond.taken;
+                cond.taken = true;
+                cond.in_else = true;
+                return Ok(String::new());
+            }
+            "endif" => {
+                if conds.pop().is_none() {
+                    return Err(PreprocessorError::UnmatchedConditional(name.to_string()));
+                }
+                return Ok(String::new());
+            }
+            _ => {}
+        }
+
+        // 読み飛ばしているグループの中は条件ディレクティブ以外を無視する
+        if !active {
+            return Ok(String::new());
+        }
+
+        match name {
+            "define" => {
+                let (name, m) = parse_define(&rest)?;
+                self.macros.insert(name, m);
+            }
+            "undef" => {
+                let name = macro_name(&rest)?.to_string();
+                self.macros.remove(&name);
+            }
+            "include" => return self.include(&rest),
+            "error" => return Err(PreprocessorError::UserError(join(&rest))),
+            "warning" => eprintln!("{}:{}: warning: {}", self.file, self.line, join(&rest)),
+            "pragma" => {
+                if let (Some(PpToken::Ident(s)), Some(path)) = (rest.first(), &self.path) {
+                    if s == "once" {
+                        if let Ok(path) = path.canonicalize() {
+                            self.pragma_once.insert(path);
+                        }
+                    }
+                }
+            }
+            "line" => {}
+            _ => return Err(PreprocessorError::InvalidDirective(name.to_string())),
+        }
+        Ok(String::new())
+    }
+
+    fn eval_condition(&self, tokens: &[PpToken]) -> Result<bool> {
+        // definedはマクロ展開より先に置き換える
+        let mut replaced = Vec::new();
+        let mut iter = tokens.iter().filter(|t| **t != PpToken::Space).peekable();
+        while let Some(t) = iter.next() {
+            if *t != PpToken::Ident("defined".to_string()) {
+                replaced.push(t.clone());
+                continue;
+            }
+            let paren = iter.peek() == Some(&&PpToken::punct("("));
+            if paren {
+                iter.next();
+            }
+            let defined = match iter.next() {
+                Some(PpToken::Ident(name)) => self.macros.contains_key(name),
+                _ => return Err(PreprocessorError::InvalidExpression),
+            };
+            if paren && iter.next() != Some(&PpToken::punct(")")) {
+                return Err(PreprocessorError::InvalidExpression);
+            }
+            replaced.push(PpToken::Num((defined as i64).to_string()));
+        }
+
+        Ok(expr::eval(&self.expand(replaced)?)? != 0)
+    }
+
+    fn include(&mut self, tokens: &[PpToken]) -> Result<String> {
+        let (name, quoted) = match parse_include_name(tokens) {
+            Some(name) => name,
+            // #include MACRO の形式
+            None => parse_include_name(&trim(&self.expand(tokens.to_vec())?))
+                .ok_or_else(|| PreprocessorError::InvalidDirective("include".to_string()))?,
+        };
+
+        let mut dirs = Vec::new();
+        if quoted {
+            let current_dir = self
+                .path
+                .as_ref()
+                .and_then(|p| p.parent())
+                .map(|p| p.to_path_buf())
+                .unwrap_or_else(|| PathBuf::from("."));
+            dirs.push(current_dir);
+        }
+        dirs.extend(self.include_paths.iter().cloned());
+
+        let path = dirs
+            .iter()
+            .map(|dir| dir.join(&name))
+            .find(|path| path.is_file())
+            .ok_or(PreprocessorError::IncludeNotFound(name))?;
+        self.preprocess_file(&path)
+    }
+
+    fn expand(&self, tokens: Vec<PpToken>) -> Result<Vec<PpToken>> {
+        let mut input = tokens
+            .into_iter()
+            .map(|token| Tok {
+                token,
+                hideset: HashSet::new(),
+            })
+            .collect::<VecDeque<_>>();
+        let mut out = Vec::new();
+
+        while let Some(tok) = input.pop_front() {
+            let name = match &tok.token {
+                PpToken::Ident(name) if !tok.hideset.contains(name) => name.clone(),
+                _ => {
+                    out.push(tok.token);
+                    continue;
+                }
+            };
+
+            match name.as_str() {
+                "__FILE__" => {
+                    out.push(PpToken::Str(quote(&self.file)));
+                    continue;
+                }
+                "__LINE__" => {
+                    out.push(PpToken::Num(self.line.to_string()));
+                    continue;
+                }
+                _ => {}
+            }
+
+            let replacement = match self.macros.get(&name) {
+                None => {
+                    out.push(tok.token);
+                    continue;
+                }
+                Some(Macro::Object(body)) => body.clone(),
+                Some(Macro::Function {
+                    params,
+                    variadic,
+                    body,
+                }) => {
+                    // 後ろに "(" がない関数形式マクロは展開しない
+                    match input.iter().find(|t| t.token != PpToken::Space) {
+                        Some(t) if t.token == PpToken::punct("(") => {}
+                        _ => {
+                            out.push(tok.token);
+                            continue;
+                        }
+                    }
+                    while input.pop_front().unwrap().token != PpToken::punct("(") {}
+
+                    let args = collect_args(&mut input, &name, params.len(), *variadic)?;
+                    self.substitute(body, params, &args)?
+                }
+            };
+
+            let mut hideset = tok.hideset;
+            hideset.insert(name);
+            for token in replacement.into_iter().rev() {
+                input.push_front(Tok {
+                    token,
+                    hideset: hideset.clone(),
+                });
+            }
+        }
+
+        Ok(out)
+    }
+
+    // 関数形式マクロの本体の仮引数を実引数で置き換える
+    fn substitute(
+        &self,
+        body: &[PpToken],
+        params: &[String],
+        args: &[Vec<PpToken>],
+    ) -> Result<Vec<PpToken>> {
+        let param_index = |t: &PpToken| match t {
+            PpToken::Ident(s) => params.iter().position(|p| p == s),
+            _ => None,
+        };
+        let next_non_space = |from: usize| (from..body.len()).find(|&j| body[j] != PpToken::Space);
+
+        let mut out: Vec<PpToken> = Vec::new();
+        // "##" の左辺が空の引数だったかどうか
+        let mut placemarker = false;
+        let mut i = 0;
+        while i < body.len() {
+            let token = &body[i];
+
+            if *token == PpToken::punct("#") {
+                let j = next_non_space(i + 1).ok_or(PreprocessorError::InvalidMacroDefinition)?;
+                let k = param_index(&body[j]).ok_or(PreprocessorError::InvalidMacroDefinition)?;
+                out.push(PpToken::Str(stringify(&args[k])));
+                i = j + 1;
+                continue;
+            }
+
+            if *token == PpToken::punct("##") {
+                while out.last() == Some(&PpToken::Space) {
+                    out.pop();
+                }
+                let j = next_non_space(i + 1).ok_or(PreprocessorError::InvalidMacroDefinition)?;
+                let rhs = match param_index(&body[j]) {
+                    Some(k) => args[k].clone(),
+                    None => vec![body[j].clone()],
+                };
+                let lhs = if placemarker { None } else { out.pop() };
+                match (lhs, rhs.split_first()) {
+                    // GNU拡張: , ## __VA_ARGS__ で可変長引数が空ならカンマを消す
+                    (Some(lhs), None)
+                        if lhs == PpToken::punct(",") && body[j] == PpToken::Ident("__VA_ARGS__".to_string()) => {}
+                    (Some(lhs), None) => out.push(lhs),
+                    (Some(lhs), Some((first, rest))) => {
+                        out.extend(pp_tokenize(&format!("{}{}", lhs.text(), first.text())));
+                        out.extend(rest.iter().cloned());
+                    }
+                    (None, _) => out.extend(rhs),
+                }
+                placemarker = false;
+                i = j + 1;
+                continue;
+            }
+
+            if let Some(k) = param_index(token) {
+                // "##" のオペランドになる引数は展開しない
+                let pasted = next_non_space(i + 1).is_some_and(|j| body[j] == PpToken::punct("##"));
+                if pasted {
+                    placemarker = args[k].is_empty();
+                    out.extend(args[k].iter().cloned());
+                } else {
+                    out.extend(self.expand(args[k].clone())?);
+                }
+                i += 1;
+                continue;
+            }
+
+            out.push(token.clone());
+            i += 1;
+        }
+        Ok(out)
+    }
+}
+
+// 関数形式マクロの実引数を ")" まで読む
+// 可変長引数の場合、固定引数より後ろはカンマごと1つの引数にまとめる
+fn collect_args(
+    input: &mut VecDeque<Tok>,
+    name: &str,
+    param_count: usize,
+    variadic: bool,
+) -> Result<Vec<Vec<PpToken>>> {
+    let fixed = if variadic { param_count - 1 } else { param_count };
+    let mut args = Vec::new();
+    let mut arg = Vec::new();
+    let mut depth = 0;
+    loop {
+        let token = match input.pop_front() {
+            Some(t) => t.token,
+            None => return Err(PreprocessorError::UnterminatedArguments(name.to_string())),
+        };
+        match token.text() {
+            "(" => depth += 1,
+            ")" if depth == 0 => break,
+            ")" => depth -= 1,
+            "," if depth == 0 && !(variadic && args.len() == fixed) => {
+                args.push(trim(&arg));
+                arg = Vec::new();
+                continue;
+            }
+            _ => {}
+        }
+        arg.push(token);
+    }
+    args.push(trim(&arg));
+
+    // 引数なしの呼び出し f() は空の引数1つとして読まれる
+    if param_count == 0 && args.len() == 1 && args[0].is_empty() {
+        args.clear();
+    }
+    if variadic && args.len() == fixed {
+        args.push(Vec::new());
+    }
+    if args.len() != param_count {
+        return Err(PreprocessorError::WrongNumberOfArguments(name.to_string()));
+    }
+    Ok(args)
+}
+
+fn parse_define(tokens: &[PpToken]) -> Result<(String, Macro)> {
+    let name = match tokens.first() {
+        Some(PpToken::Ident(name)) => name.clone(),
+        _ => return Err(PreprocessorError::InvalidMacroDefinition),
+    };
+
+    // マクロ名の直後に空白なしで "(" が続くときだけ関数形式マクロ
+    if tokens.get(1) != Some(&PpToken::punct("(")) {
+        return Ok((name, Macro::Object(trim(&tokens[1..]))));
+    }
+
+    let mut params = Vec::new();
+    let mut variadic = false;
+    let mut iter = tokens[2..].iter().filter(|t| **t != PpToken::Space);
+    let mut rest = 2;
+    loop {
+        let token = iter.next().ok_or(PreprocessorError::InvalidMacroDefinition)?;
+        match token {
+            PpToken::Punct(p) if p == ")" && params.is_empty() && !variadic => {}
+            PpToken::Ident(param) if !variadic => {
+                params.push(param.clone());
+                match iter.next().map(|t| t.text()) {
+                    Some(",") => continue,
+                    Some(")") => {}
+                    _ => return Err(PreprocessorError::InvalidMacroDefinition),
+                }
+            }
+            PpToken::Punct(p) if p == "..." && !variadic => {
+                variadic = true;
+                params.push("__VA_ARGS__".to_string());
+                if iter.next().map(|t| t.text()) != Some(")") {
+                    return Err(PreprocessorError::InvalidMacroDefinition);
+                }
+            }
+            _ => return Err(PreprocessorError::InvalidMacroDefinition),
+        }
+        break;
+    }
+    // 仮引数リストの ")" の次から本体
+    let mut depth = 0;
+    for (i, t) in tokens.iter().enumerate().skip(1) {
+        match t.text() {
+            "(" => depth += 1,
+            ")" => {
+                depth -= 1;
+                if depth == 0 {
+                    rest = i + 1;
+                    break;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok((
+        name,
+        Macro::Function {
+            params,
+            variadic,
+            body: trim(&tokens[rest..]),
+        },
+    ))
+}
+
+fn macro_name(tokens: &[PpToken]) -> Result<&str> {
+    match tokens.first() {
+        Some(PpToken::Ident(name)) => Ok(name),
+        _ => Err(PreprocessorError::InvalidMacroDefinition),
+    }
+}
+
+// "file" なら (file, true)、<file> なら (file, false)
+fn parse_include_name(tokens: &[PpToken]) -> Option<(String, bool)> {
+    match tokens.first()? {
+        PpToken::Str(s) if s.starts_with('"') && s.len() >= 2 => {
+            Some((s[1..s.len() - 1].to_string(), true))
+        }
+        PpToken::Punct(p) if p == "<" => {
+            let end = tokens.iter().position(|t| *t == PpToken::punct(">"))?;
+            Some((join(&tokens[1..end]), false))
+        }
+        _ => None,
+    }
+}
+
+// コメントを空白に置き換える
+// 行番号がずれないようにブロックコメント中の改行は残す
+fn remove_comments(src: &str) -> Result<String> {
+    let mut out = String::new();
+    let mut chars = src.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' | '\'' => {
+                out.push(c);
+                while let Some(d) = chars.next() {
+                    out.push(d);
+                    if d == '\\' {
+                        if let Some(e) = chars.next() {
+                            out.push(e);
+                        }
+                    } else if d == c || d == '\n' {
+                        break;
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'/') => {
+                while chars.peek().is_some_and(|d| *d != '\n') {
+                    chars.next();
+                }
+                out.push(' ');
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut terminated = false;
+                while let Some(d) = chars.next() {
+                    if d == '*' && chars.peek() == Some(&'/') {
+                        chars.next();
+                        terminated = true;
+                        break;
+                    }
+                    if d == '\n' {
+                        out.push('\n');
+                    }
+                }
+                if !terminated {
+                    return Err(PreprocessorError::UnterminatedComment);
+                }
+                out.push(' ');
+            }
+            _ => out.push(c),
+        }
+    }
+    Ok(out)
+}
+
+// バックスラッシュ改行で行をつないだ論理行に分ける
+// (開始行番号, 行の内容, 元の物理行数) を返す
+fn logical_lines(src: &str) -> Vec<(usize, String, usize)> {
+    let mut lines = Vec::new();
+    let mut physical = src.split('\n').enumerate().peekable();
+    while let Some((i, line)) = physical.next() {
+        let mut text = line.to_string();
+        let mut count = 1;
+        while text.ends_with('\\') {
+            text.pop();
+            match physical.next() {
+                Some((_, next)) => {
+                    text.push_str(next);
+                    count += 1;
+                }
+                None => break,
+            }
+        }
+        // 末尾の改行の後ろは空の行として数えない
+        if physical.peek().is_none() && text.is_empty() && count == 1 {
+            break;
+        }
+        lines.push((i + 1, text, count));
+    }
+    lines
+}
+
+fn pp_tokenize(s: &str) -> Vec<PpToken> {
+    const PUNCTS: [&str; 19] = [
+        "...", "<<=", ">>=", "##", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "++", "--",
+        "->", "+=", "-=", "*=", "/=",
+    ];
+
+    let mut tokens = Vec::new();
+    let mut rest = s;
+    while let Some(c) = rest.chars().next() {
+        if c.is_whitespace() {
+            rest = rest.trim_start();
+            if tokens.last() != Some(&PpToken::Space) {
+                tokens.push(PpToken::Space);
+            }
+            continue;
+        }
+
+        let is_number = c.is_ascii_digit()
+            || (c == '.' && rest[1..].starts_with(|d: char| d.is_ascii_digit()));
+        if is_number {
+            // pp-number: 数字で始まり英数字, '.', 指数部の符号が続くもの
+            let mut end = 0;
+            let mut prev = ' ';
+            for (i, d) in rest.char_indices() {
+                let exponent_sign = (d == '+' || d == '-') && matches!(prev, 'e' | 'E' | 'p' | 'P');
+                if !(d.is_alphanumeric() || d == '_' || d == '.' || exponent_sign) {
+                    break;
+                }
+                end = i + d.len_utf8();
+                prev = d;
+            }
+            tokens.push(PpToken::Num(rest[..end].to_string()));
+            rest = &rest[end..];
+            continue;
+        }
+
+        if c.is_alphabetic() || c == '_' {
+            let end = rest
+                .find(|d: char| !(d.is_alphanumeric() || d == '_'))
+                .unwrap_or(rest.len());
+            tokens.push(PpToken::Ident(rest[..end].to_string()));
+            rest = &rest[end..];
+            continue;
+        }
+
+        if c == '"' || c == '\'' {
+            let mut end = rest.len();
+            let mut escaped = false;
+            for (i, d) in rest.char_indices().skip(1) {
+                if escaped {
+                    escaped = false;
+                } else if d == '\\' {
+                    escaped = true;
+                } else if d == c {
+                    end = i + 1;
+                    break;
+                }
+            }
+            tokens.push(PpToken::Str(rest[..end].to_string()));
+            rest = &rest[end..];
+            continue;
+        }
+
+        let punct = PUNCTS
+            .iter()
+            .find(|p| rest.starts_with(**p))
+            .map(|p| p.to_string())
+            .unwrap_or_else(|| c.to_string());
+        rest = &rest[punct.len()..];
+        tokens.push(PpToken::Punct(punct));
+    }
+    tokens
+}
+
+fn trim(tokens: &[PpToken]) -> Vec<PpToken> {
+    let start = tokens.iter().position(|t| *t != PpToken::Space);
+    let end = tokens.iter().rposition(|t| *t != PpToken::Space);
+    match (start, end) {
+        (Some(start), Some(end)) => tokens[start..=end].to_vec(),
+        _ => Vec::new(),
+    }
+}
+
+fn join(tokens: &[PpToken]) -> String {
+    tokens.iter().map(|t| t.text()).collect()
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// # 演算子: 実引数を文字列リテラルにする
+fn stringify(tokens: &[PpToken]) -> String {
+    let text = tokens
+        .iter()
+        .map(|t| match t {
+            PpToken::Str(s) => s.replace('\\', "\\\\").replace('"', "\\\""),
+            t => t.text().to_string(),
+        })
+        .collect::<String>();
+    format!("\"{}\"", text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn preprocess(input: &str) -> Result<String> {
+        Preprocessor::new(Vec::new()).preprocess(input, "test.c")
+    }
+
+    #[test]
+    fn test_preprocess() {
+        struct Test {
+            name: &'static str,
+            input: &'static str,
+            expected: &'static str,
+        }
+
+        let tests = vec![
+            Test {
+                name: "no directive",
+                input: "a = 1;\nreturn a;\n",
+                expected: "a = 1;\nreturn a;\n",
+            },
+            Test {
+                name: "comment",
+                input: "a = 1; // comment\n/* multi\nline */ return a;\n",
+                expected: "a = 1; \n\n return a;\n",
+            },
+            Test {
+                name: "object-like macro",
+                input: "#define N 10\nreturn N * N;\n",
+                expected: "\nreturn 10 * 10;\n",
+            },
+            Test {
+                name: "nested object-like macro",
+                input: "#define A B + 1\n#define B 2\nA;\n",
+                expected: "\n\n2 + 1;\n",
+            },
+            Test {
+                name: "self-referential macro",
+                input: "#define x (x + 1)\nx;\n",
+                expected: "\n(x + 1);\n",
+            },
+            Test {
+                name: "function-like macro",
+                input: "#define MAX(a, b) ((a) > (b) ? (a) : (b))\nMAX(1, 2 * 3);\n",
+                expected: "\n((1) > (2 * 3) ? (1) : (2 * 3));\n",
+            },
+            Test {
+                name: "function-like macro without arguments",
+                input: "#define F() 42\n#define G (1)\nF(); G;\n",
+                expected: "\n\n42; (1);\n",
+            },
+            Test {
+                name: "function-like macro name without parenthesis",
+                input: "#define F(x) x\nF;\n",
+                expected: "\nF;\n",
+            },
+            Test {
+                name: "nested parenthesis in arguments",
+                input: "#define F(x, y) x - y\nF((1, 2), 3);\n",
+                expected: "\n(1, 2) - 3;\n",
+            },
+            Test {
+                name: "rescan",
+                input: "#define F(x) x + 1\n#define G F\nG(2);\n",
+                expected: "\n\n2 + 1;\n",
+            },
+            Test {
+                name: "stringify",
+                input: "#define S(x) #x\nS(a  +  \"b\");\n",
+                expected: "\n\"a + \\\"b\\\"\";\n",
+            },
+            Test {
+                name: "token pasting",
+                input: "#define CAT(a, b) a ## b\nCAT(foo, 12) = CAT(1, 2);\n",
+                expected: "\nfoo12 = 12;\n",
+            },
+            Test {
+                name: "token pasting with empty argument",
+                input: "#define CAT(a, b) a ## b\nCAT(, x); CAT(x, );\n",
+                expected: "\nx; x;\n",
+            },
+            Test {
+                name: "variadic macro",
+                input: "#define F(fmt, ...) f(fmt, __VA_ARGS__)\nF(1, 2, 3);\n",
+                expected: "\nf(1, 2, 3);\n",
+            },
+            Test {
+                name: "variadic macro with comma swallowing",
+                input: "#define F(fmt, ...) f(fmt , ## __VA_ARGS__)\nF(1);\n",
+                expected: "\nf(1 );\n",
+            },
+            Test {
+                name: "multi-line macro invocation",
+                input: "#define F(a, b) a + b\nF(1,\n2);\nx;\n",
+                expected: "\n1 + 2;\n\nx;\n",
+            },
+            Test {
+                name: "line continuation",
+                input: "#define A 1 \\\n + 2\nA;\n",
+                expected: "\n\n1 + 2;\n",
+            },
+            Test {
+                name: "undef",
+                input: "#define A 1\n#undef A\nA;\n",
+                expected: "\n\nA;\n",
+            },
+            Test {
+                name: "ifdef",
+                input: "#define A\n#ifdef A\n1;\n#else\n2;\n#endif\n#ifndef A\n3;\n#endif\n",
+                expected: "\n\n1;\n\n\n\n\n\n\n",
+            },
+            Test {
+                name: "if elif else",
+                input: "#if 1 + 1 == 3\n1;\n#elif defined(B) || 2 > 1\n2;\n#elif 1\n3;\n#else\n4;\n#endif\n",
+                expected: "\n\n\n2;\n\n\n\n\n\n",
+            },
+            Test {
+                name: "nested if",
+                input: "#if 0\n#if 1\n1;\n#endif\n#else\n#if 1\n2;\n#endif\n#endif\n",
+                expected: "\n\n\n\n\n\n2;\n\n\n",
+            },
+            Test {
+                name: "if with macro",
+                input: "#define V 3\n#if V * 2 == 6 && !defined UNDEFINED\n1;\n#endif\n",
+                expected: "\n\n1;\n\n",
+            },
+            Test {
+                name: "short-circuit",
+                input: "#if 1 || 1/0\n1;\n#endif\n#if 0 && 1/0\n#else\n2;\n#endif\n#if 1 ? 2 : 1/0\n3;\n#endif\n",
+                expected: "\n1;\n\n\n\n2;\n\n\n3;\n\n",
+            },
+            Test {
+                name: "invalid directive in skipped group",
+                input: "#if 0\n#foo\n#error unreachable\n#endif\n",
+                expected: "\n\n\n\n",
+            },
+            Test {
+                name: "non-identifier directive in skipped group",
+                input: "#if 0\n# 1 \"file\"\n#endif\n",
+                expected: "\n\n\n",
+            },
+            Test {
+                name: "predefined macros",
+                input: "__STDC__; __LINE__;\n__LINE__; __FILE__;\n",
+                expected: "1; 1;\n2; \"test.c\";\n",
+            },
+        ];
+
+        for t in tests {
+            assert_eq!(
+                preprocess(t.input),
+                Ok(t.expected.to_string()),
+                "Failed in the {}",
+                t.name
+            );
+        }
+    }
+
+    #[test]
+    fn test_preprocess_error() {
+        struct Test {
+            name: &'static str,
+            input: &'static str,
+            expected: PreprocessorError,
+        }
+
+        let tests = vec![
+            Test {
+                name: "#error",
+                input: "#error something wrong\n",
+                expected: PreprocessorError::UserError("something wrong".to_string()),
+            },
+            Test {
+                name: "unterminated if",
+                input: "#if 1\n1;\n",
+                expected: PreprocessorError::UnterminatedConditional,
+            },
+            Test {
+                name: "endif without if",
+                input: "#endif\n",
+                expected: PreprocessorError::UnmatchedConditional("endif".to_string()),
+            },
+            Test {
+                name: "else after else",
+                input: "#if 1\n#else\n#else\n#endif\n",
+                expected: PreprocessorError::UnmatchedConditional("else".to_string()),
+            },
+            Test {
+                name: "unknown directive",
+                input: "#foo\n",
+                expected: PreprocessorError::InvalidDirective("foo".to_string()),
+            },
+            Test {
+                name: "non-identifier directive",
+                input: "# 1 \"file\"\n",
+                expected: PreprocessorError::InvalidDirective("1".to_string()),
+            },
+            Test {
+                name: "wrong number of arguments",
+                input: "#define F(a, b) a\nF(1);\n",
+                expected: PreprocessorError::WrongNumberOfArguments("F".to_string()),
+            },
+            Test {
+                name: "unterminated arguments",
+                input: "#define F(a) a\nF(1;\n",
+                expected: PreprocessorError::UnterminatedArguments("F".to_string()),
+            },
+            Test {
+                name: "division by zero",
+                input: "#if 1 / 0\n#endif\n",
+                expected: PreprocessorError::DivisionByZero,
+            },
+            Test {
+                name: "lone quote in #if",
+                input: "#if '\n#endif\n",
+                expected: PreprocessorError::InvalidExpression,
+            },
+            Test {
+                name: "include not found",
+                input: "#include \"not_found.h\"\n",
+                expected: PreprocessorError::IncludeNotFound("not_found.h".to_string()),
+            },
+        ];
+
+        for t in tests {
+            assert_eq!(preprocess(t.input), Err(t.expected), "Failed in the {}", t.name);
+        }
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = std::env::temp_dir().join(format!("rust9cc_pp_{}", std::process::id()));
+        fs::create_dir_all(dir.join("sys")).unwrap();
+        fs::write(dir.join("a.h"), "#pragma once\n#define A 1\na;\n").unwrap();
+        fs::write(dir.join("sys/b.h"), "#include \"a.h\"\nb = A;\n").unwrap();
+
+        let mut pp = Preprocessor::new(vec![dir.clone()]);
+        let out = pp
+            .preprocess("#include <sys/b.h>\n#include <a.h>\nA;\n", "test.c")
+            .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        // a.h は #pragma once なので2回目は空になる
+        assert_eq!(out, "\n\na;\n\nb = 1;\n\n\n1;\n");
+    }
+}
@@ -0,0 +1,297 @@
+use super::{PpToken, PreprocessorError};
+
+type Result<T> = std::result::Result<T, PreprocessorError>;
+
+// #if / #elif の定数式を評価する
+// definedの処理とマクロ展開は呼び出し側で済ませておき、残った識別子は0として扱う
+pub fn eval(tokens: &[PpToken]) -> Result<i64> {
+    let tokens = tokens
+        .iter()
+        .filter(|t| **t != PpToken::Space)
+        .cloned()
+        .collect::<Vec<_>>();
+    let mut evaluator = Evaluator {
+        tokens,
+        pos: 0,
+        skipping: 0,
+    };
+    let value = evaluator.conditional()?;
+    if evaluator.pos != evaluator.tokens.len() {
+        return Err(PreprocessorError::InvalidExpression);
+    }
+    Ok(value)
+}
+
+struct Evaluator {
+    tokens: Vec<PpToken>,
+    pos: usize,
+    skipping: usize, // 評価しないオペランドの中にいる深さ
+}
+
+impl Evaluator {
+    fn consume(&mut self, punct: &str) -> bool {
+        match self.tokens.get(self.pos) {
+            Some(PpToken::Punct(p)) if p == punct => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // && や || 、?: で評価されないオペランドを読む
+    // 構文は検査するが、0除算などの評価時のエラーは出さない
+    fn skip(&mut self, f: impl FnOnce(&mut Self) -> Result<i64>) -> Result<i64> {
+        self.skipping += 1;
+        let value = f(self);
+        self.skipping -= 1;
+        value
+    }
+
+    fn expect(&mut self, punct: &str) -> Result<()> {
+        if self.consume(punct) {
+            Ok(())
+        } else {
+            Err(PreprocessorError::InvalidExpression)
+        }
+    }
+
+    // conditional = logor ("?" conditional ":" conditional)?
+    fn conditional(&mut self) -> Result<i64> {
+        let cond = self.logor()?;
+        if !self.consume("?") {
+            return Ok(cond);
+        }
+        let then = if cond != 0 {
+            self.conditional()?
+        } else {
+            self.skip(Self::conditional)?
+        };
+        self.expect(":")?;
+        let els = if cond != 0 {
+            self.skip(Self::conditional)?
+        } else {
+            self.conditional()?
+        };
+        Ok(if cond != 0 { then } else { els })
+    }
+
+    // logor = logand ("||" logand)*
+    fn logor(&mut self) -> Result<i64> {
+        let mut value = self.logand()?;
+        while self.consume("||") {
+            let r = if value != 0 {
+                self.skip(Self::logand)?
+            } else {
+                self.logand()?
+            };
+            value = (value != 0 || r != 0) as i64;
+        }
+        Ok(value)
+    }
+
+    // logand = bitor ("&&" bitor)*
+    fn logand(&mut self) -> Result<i64> {
+        let mut value = self.bitor()?;
+        while self.consume("&&") {
+            let r = if value == 0 {
+                self.skip(Self::bitor)?
+            } else {
+                self.bitor()?
+            };
+            value = (value != 0 && r != 0) as i64;
+        }
+        Ok(value)
+    }
+
+    // bitor = bitxor ("|" bitxor)*
+    fn bitor(&mut self) -> Result<i64> {
+        let mut value = self.bitxor()?;
+        while self.consume("|") {
+            value |= self.bitxor()?;
+        }
+        Ok(value)
+    }
+
+    // bitxor = bitand ("^" bitand)*
+    fn bitxor(&mut self) -> Result<i64> {
+        let mut value = self.bitand()?;
+        while self.consume("^") {
+            value ^= self.bitand()?;
+        }
+        Ok(value)
+    }
+
+    // bitand = equality ("&" equality)*
+    fn bitand(&mut self) -> Result<i64> {
+        let mut value = self.equality()?;
+        while self.consume("&") {
+            value &= self.equality()?;
+        }
+        Ok(value)
+    }
+
+    // equality = relational ("==" relational | "!=" relational)*
+    fn equality(&mut self) -> Result<i64> {
+        let mut value = self.relational()?;
+        loop {
+            if self.consume("==") {
+                value = (value == self.relational()?) as i64;
+            } else if self.consume("!=") {
+                value = (value != self.relational()?) as i64;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    // relational = shift ("<" shift | "<=" shift | ">" shift | ">=" shift)*
+    fn relational(&mut self) -> Result<i64> {
+        let mut value = self.shift()?;
+        loop {
+            if self.consume("<") {
+                value = (value < self.shift()?) as i64;
+            } else if self.consume("<=") {
+                value = (value <= self.shift()?) as i64;
+            } else if self.consume(">") {
+                value = (value > self.shift()?) as i64;
+            } else if self.consume(">=") {
+                value = (value >= self.shift()?) as i64;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    // shift = add ("<<" add | ">>" add)*
+    fn shift(&mut self) -> Result<i64> {
+        let mut value = self.add()?;
+        loop {
+            if self.consume("<<") {
+                value = value.wrapping_shl(self.add()? as u32);
+            } else if self.consume(">>") {
+                value = value.wrapping_shr(self.add()? as u32);
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    // add = mul ("+" mul | "-" mul)*
+    fn add(&mut self) -> Result<i64> {
+        let mut value = self.mul()?;
+        loop {
+            if self.consume("+") {
+                value = value.wrapping_add(self.mul()?);
+            } else if self.consume("-") {
+                value = value.wrapping_sub(self.mul()?);
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    // mul = unary ("*" unary | "/" unary | "%" unary)*
+    fn mul(&mut self) -> Result<i64> {
+        let mut value = self.unary()?;
+        loop {
+            if self.consume("*") {
+                value = value.wrapping_mul(self.unary()?);
+            } else if self.consume("/") {
+                let r = self.unary()?;
+                value = self.division(value.checked_div(r))?;
+            } else if self.consume("%") {
+                let r = self.unary()?;
+                value = self.division(value.checked_rem(r))?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    // 0除算はエラー、ただし評価しないオペランドの中では0にしておく
+    fn division(&self, value: Option<i64>) -> Result<i64> {
+        match value {
+            Some(value) => Ok(value),
+            None if self.skipping > 0 => Ok(0),
+            None => Err(PreprocessorError::DivisionByZero),
+        }
+    }
+
+    // unary = ("+" | "-" | "!" | "~") unary | primary
+    fn unary(&mut self) -> Result<i64> {
+        if self.consume("+") {
+            self.unary()
+        } else if self.consume("-") {
+            Ok(self.unary()?.wrapping_neg())
+        } else if self.consume("!") {
+            Ok((self.unary()? == 0) as i64)
+        } else if self.consume("~") {
+            Ok(!self.unary()?)
+        } else {
+            self.primary()
+        }
+    }
+
+    // primary = num | char | ident | "(" conditional ")"
+    fn primary(&mut self) -> Result<i64> {
+        if self.consume("(") {
+            let value = self.conditional()?;
+            self.expect(")")?;
+            return Ok(value);
+        }
+
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or(PreprocessorError::InvalidExpression)?;
+        self.pos += 1;
+        match token {
+            PpToken::Num(n) => parse_integer(&n),
+            PpToken::Str(s) if s.starts_with('\'') => parse_char(&s),
+            // マクロ展開後に残った識別子は0
+            PpToken::Ident(_) => Ok(0),
+            _ => Err(PreprocessorError::InvalidExpression),
+        }
+    }
+}
+
+fn parse_integer(s: &str) -> Result<i64> {
+    let digits = s.trim_end_matches(['u', 'U', 'l', 'L']);
+    let parsed = if let Some(hex) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        u64::from_str_radix(hex, 16)
+    } else if digits.len() > 1 && digits.starts_with('0') {
+        u64::from_str_radix(&digits[1..], 8)
+    } else {
+        digits.parse::<u64>()
+    };
+    parsed
+        .map(|n| n as i64)
+        .map_err(|_| PreprocessorError::InvalidExpression)
+}
+
+fn parse_char(s: &str) -> Result<i64> {
+    // 閉じていない ' だけのトークンもここに来る
+    if s.len() < 2 || !s.ends_with('\'') {
+        return Err(PreprocessorError::InvalidExpression);
+    }
+    let inner = &s[1..s.len() - 1];
+    let mut chars = inner.chars();
+    let value = match chars.next() {
+        Some('\\') => match chars.next() {
+            Some('n') => '\n' as i64,
+            Some('t') => '\t' as i64,
+            Some('r') => '\r' as i64,
+            Some('0') => 0,
+            Some(c) => c as i64,
+            None => return Err(PreprocessorError::InvalidExpression),
+        },
+        Some(c) => c as i64,
+        None => return Err(PreprocessorError::InvalidExpression),
+    };
+    Ok(value)
+}
@@ -0,0 +1,229 @@
+use thiserror::Error;
+
+use crate::bytecode::{Op, Program, MAX_SLOTS};
+
+#[derive(Debug, Error, PartialEq)]
+pub enum VmError {
+    #[error("read of uninitialized variable slot {0}")]
+    UninitializedVariable(u8),
+    #[error("variable slot out of range: {0}")]
+    InvalidSlot(u8),
+    #[error("division by zero")]
+    DivisionByZero,
+    #[error("signed integer overflow")]
+    Overflow,
+    #[error("stack underflow at {0}")]
+    StackUnderflow(usize),
+    #[error("jump out of the program: {0}")]
+    InvalidJump(u32),
+}
+
+type Result<T> = std::result::Result<T, VmError>;
+
+// バイトコードを実行するスタックマシン
+pub struct Vm<'a> {
+    program: &'a Program,
+    pc: usize,
+    stack: Vec<i64>,
+    slots: Vec<Option<i64>>, // Noneは未初期化
+    last: i64,               // 最後にPopした値
+}
+
+impl<'a> Vm<'a> {
+    pub fn new(program: &'a Program) -> Self {
+        Self {
+            program,
+            pc: 0,
+            stack: Vec::new(),
+            slots: vec![None; program.slots as usize],
+            last: 0,
+        }
+    }
+
+    // 終了するまで実行して main の戻り値を返す
+    pub fn run(&mut self) -> Result<i64> {
+        if self.program.slots as usize > MAX_SLOTS {
+            return Err(VmError::InvalidSlot(self.program.slots));
+        }
+        loop {
+            let op = *self
+                .program
+                .code
+                .get(self.pc)
+                .ok_or(VmError::InvalidJump(self.pc as u32))?;
+            self.pc += 1;
+            match op {
+                Op::Push(n) => self.stack.push(n),
+                Op::Load(slot) => {
+                    let value = self
+                        .slot(slot)?
+                        .ok_or(VmError::UninitializedVariable(slot))?;
+                    self.stack.push(value);
+                }
+                Op::Store(slot) => {
+                    let value = *self.stack.last().ok_or(VmError::StackUnderflow(self.pc - 1))?;
+                    self.slot(slot)?;
+                    self.slots[slot as usize] = Some(value);
+                }
+                Op::Pop => self.last = self.pop()?,
+                Op::Drop => {
+                    self.pop()?;
+                }
+                Op::Add => self.binary(|l, r| l.checked_add(r).ok_or(VmError::Overflow))?,
+                Op::Sub => self.binary(|l, r| l.checked_sub(r).ok_or(VmError::Overflow))?,
+                Op::Mul => self.binary(|l, r| l.checked_mul(r).ok_or(VmError::Overflow))?,
+                Op::Div => self.binary(|l, r| match r {
+                    0 => Err(VmError::DivisionByZero),
+                    _ => l.checked_div(r).ok_or(VmError::Overflow),
+                })?,
+                Op::Eq => self.binary(|l, r| Ok((l == r) as i64))?,
+                Op::Ne => self.binary(|l, r| Ok((l != r) as i64))?,
+                Op::Lt => self.binary(|l, r| Ok((l < r) as i64))?,
+                Op::Le => self.binary(|l, r| Ok((l <= r) as i64))?,
+                Op::Jmp(target) => self.pc = target as usize,
+                Op::Jz(target) => {
+                    if self.pop()? == 0 {
+                        self.pc = target as usize;
+                    }
+                }
+                Op::Ret => return self.pop(),
+                Op::Halt => return Ok(self.last),
+            }
+        }
+    }
+
+    fn pop(&mut self) -> Result<i64> {
+        self.stack.pop().ok_or(VmError::StackUnderflow(self.pc - 1))
+    }
+
+    fn slot(&self, slot: u8) -> Result<Option<i64>> {
+        self.slots
+            .get(slot as usize)
+            .copied()
+            .ok_or(VmError::InvalidSlot(slot))
+    }
+
+    fn binary(&mut self, f: impl Fn(i64, i64) -> Result<i64>) -> Result<()> {
+        let r = self.pop()?;
+        let l = self.pop()?;
+        self.stack.push(f(l, r)?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::parser::Parser;
+    use crate::bytecode::compile;
+    use crate::lexer::tokenize;
+
+    use super::*;
+
+    fn run(input: &str) -> Result<i64> {
+        let tokens = tokenize(&mut input.chars().peekable()).unwrap();
+        let nodes = Parser::new(tokens.iter()).parse().unwrap();
+        let program = compile(&nodes).unwrap();
+        Vm::new(&program).run()
+    }
+
+    #[test]
+    fn test_run() {
+        struct Test {
+            name: &'static str,
+            input: &'static str,
+            expected: Result<i64>,
+        }
+
+        let tests = vec![
+            Test {
+                name: "arithmetic",
+                input: "5+6*7;",
+                expected: Ok(47),
+            },
+            Test {
+                name: "if else",
+                input: "if (1 > 2) return 7; else return 14; return 0;",
+                expected: Ok(14),
+            },
+            Test {
+                name: "for",
+                input: "x = 0; for (i = 0; i < 10; i = i + 1) {x = x + 1;} return x;",
+                expected: Ok(10),
+            },
+            Test {
+                name: "while",
+                input: "x = 0; i = 0; while (i < 10) {x = x + 2; i = i + 1;} x;",
+                expected: Ok(20),
+            },
+            Test {
+                name: "for without condition",
+                input: "for (i = 0; ; i = i + 1) if (i == 7) return i;",
+                expected: Ok(7),
+            },
+            Test {
+                name: "for clauses are not expression statements",
+                input: "x = 5; for (i = 0; i < 3; i = i + 1) {}",
+                expected: Ok(5),
+            },
+            Test {
+                name: "for without expression statements",
+                input: "for (i = 0; i < 3; i = i + 1) {}",
+                expected: Ok(0),
+            },
+            Test {
+                name: "uninitialized variable",
+                input: "a = 1; return a + b;",
+                expected: Err(VmError::UninitializedVariable(1)),
+            },
+            Test {
+                name: "division by zero",
+                input: "a = 0; return 1 / a;",
+                expected: Err(VmError::DivisionByZero),
+            },
+        ];
+
+        for t in tests {
+            assert_eq!(run(t.input), t.expected, "Failed in the {}", t.name);
+        }
+    }
+
+    #[test]
+    fn test_run_invalid_program() {
+        struct Test {
+            name: &'static str,
+            program: Program,
+            expected: VmError,
+        }
+
+        let tests = vec![
+            Test {
+                name: "stack underflow",
+                program: Program {
+                    slots: 0,
+                    code: vec![Op::Push(1), Op::Add],
+                },
+                expected: VmError::StackUnderflow(1),
+            },
+            Test {
+                name: "slot out of range",
+                program: Program {
+                    slots: 1,
+                    code: vec![Op::Load(1)],
+                },
+                expected: VmError::InvalidSlot(1),
+            },
+            Test {
+                name: "jump out of the program",
+                program: Program {
+                    slots: 0,
+                    code: vec![Op::Jmp(5)],
+                },
+                expected: VmError::InvalidJump(5),
+            },
+        ];
+
+        for t in tests {
+            assert_eq!(Vm::new(&t.program).run(), Err(t.expected), "Failed in the {}", t.name);
+        }
+    }
+}
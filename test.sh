@@ -1,14 +1,55 @@
 #!/bin/bash
 
 rust9cc="./target/debug/rust9cc"
+# OPT=-O ./test.sh のようにするとx86-64でレジスタ割り当てを有効にしてテストする
+opt="${OPT:-}"
+
+# TARGET=aarch64 ./test.sh や TARGET=riscv64 ./test.sh のようにするとクロスコンパイルしてqemuで実行する
+# TARGET=wasm32 では .wasm を出力してnodeで実行する
+# TARGET=interp ではコンパイルせずにインタプリタで、TARGET=vm ではバイトコードにしてVMで実行する
+# TARGET=jit ではアセンブラを通さずにメモリ上で機械語を実行する
+target="${TARGET:-x86_64}"
+case "$target" in
+    x86_64) cc="cc"; run="" ;;
+    aarch64) cc="aarch64-linux-gnu-gcc -static"; run="qemu-aarch64" ;;
+    riscv64) cc="riscv64-linux-gnu-gcc -static"; run="qemu-riscv64" ;;
+    wasm32) run="run_wasm" ;;
+    interp | vm | jit) ;;
+    *) echo "unknown target: $target"; exit 1 ;;
+esac
+if [ -n "$opt" ] && [ "$target" != x86_64 ] && [ "$target" != jit ]; then
+    echo "OPT is only supported with TARGET=x86_64 or TARGET=jit"
+    exit 1
+fi
+
+run_wasm() {
+    node -e '
+const bytes = require("fs").readFileSync(process.argv[1]);
+const instance = new WebAssembly.Instance(new WebAssembly.Module(bytes));
+process.exit(instance.exports.main());
+' "$1"
+}
 
 try() {
     expected="$1"
     input="$2"
 
-    ${rust9cc} "$input" > tmp.s
-    cc -o tmp tmp.s
-    ./tmp
+    if [ "$target" = interp ]; then
+        ${rust9cc} --interpret "$input"
+    elif [ "$target" = vm ]; then
+        ${rust9cc} --emit=bytecode -o tmp "$input"
+        ${rust9cc} run tmp
+    elif [ "$target" = jit ]; then
+        ${rust9cc} run ${opt} "$input"
+    else
+        if [ "$target" = wasm32 ]; then
+            ${rust9cc} ${opt} --target="$target" -c -o tmp "$input"
+        else
+            ${rust9cc} ${opt} --target="$target" "$input" > tmp.s
+            ${cc} -o tmp tmp.s
+        fi
+        ${run} ./tmp
+    fi
     actual="$?"
 
   if [ "$actual" = "$expected" ]; then
@@ -19,6 +60,112 @@ try() {
   fi
 }
 
+# 外部のアセンブラを通さず、-c で出力したオブジェクトファイルをリンクする
+try_obj() {
+    expected="$1"
+    input="$2"
+
+    ${rust9cc} ${opt} -c -o tmp.o "$input"
+    cc -o tmp tmp.o
+    ./tmp
+    actual="$?"
+
+  if [ "$actual" = "$expected" ]; then
+    echo "[obj] $input => $actual"
+  else
+    echo "[obj] $input => $expected expected, but got $actual"
+    exit 1
+  fi
+}
+
+# -masm=att でAT&T記法のアセンブリを出力する
+try_att() {
+    expected="$1"
+    input="$2"
+
+    ${rust9cc} ${opt} -masm=att "$input" > tmp.s
+    cc -o tmp tmp.s
+    ./tmp
+    actual="$?"
+
+  if [ "$actual" = "$expected" ]; then
+    echo "[att] $input => $actual"
+  else
+    echo "[att] $input => $expected expected, but got $actual"
+    exit 1
+  fi
+}
+
+# --emit=llvm で出力したLLVM IRをllcでコンパイルする
+try_llvm() {
+    expected="$1"
+    input="$2"
+
+    ${rust9cc} --emit=llvm "$input" > tmp.ll
+    llc -o tmp.s tmp.ll
+    cc -o tmp tmp.s
+    ./tmp
+    actual="$?"
+
+  if [ "$actual" = "$expected" ]; then
+    echo "[llvm] $input => $actual"
+  else
+    echo "[llvm] $input => $expected expected, but got $actual"
+    exit 1
+  fi
+}
+
+# --emit=c で出力したソースをもう一度コンパイルする
+try_c() {
+    expected="$1"
+    input="$2"
+
+    ${rust9cc} --emit=c "$input" > tmp.c
+    ${rust9cc} ${opt} tmp.c > tmp.s
+    cc -o tmp tmp.s
+    ./tmp
+    actual="$?"
+
+  if [ "$actual" = "$expected" ]; then
+    echo "[c] $input => $actual"
+  else
+    echo "[c] $input => $expected expected, but got $actual"
+    exit 1
+  fi
+}
+
+# cc を使わず、組み込みのリンカで静的な実行ファイルを作る
+try_static() {
+    expected="$1"
+    input="$2"
+
+    ${rust9cc} ${opt} -static -o tmp "$input"
+    ./tmp
+    actual="$?"
+
+  if [ "$actual" = "$expected" ]; then
+    echo "[static] $input => $actual"
+  else
+    echo "[static] $input => $expected expected, but got $actual"
+    exit 1
+  fi
+}
+
+# コンパイルに失敗したときは -o のファイルをそのまま残す
+try_keep_output() {
+    input="$1"
+
+    echo keep > tmp
+    ${rust9cc} ${opt} "${@:2}" -o tmp "$input" 2> /dev/null
+
+  if [ "$(cat tmp)" = keep ]; then
+    echo "[keep] $input => kept"
+  else
+    echo "[keep] $input => tmp was overwritten"
+    exit 1
+  fi
+}
+
 cargo build
 
 try 0 "0;"
@@ -45,10 +192,72 @@ try 7 'if (1 < 2) return 7; return 14;'
 try 14 'if (1 > 2) return 7; return 14;'
 try 7 'if (1 < 2) return 7;else return 14;'
 try 14 'if (1 > 2) return 7;else return 14; return 0;'
+try 3 'if (1 < 2) 3; else 4;'
+try 4 'if (1 > 2) 3; else 4;'
 try 13 'x = 0; while (x < 13) x = x + 1; return x;'
 try 10 'x = 0; for (i = 0; i < 10; i = i + 1) x = x + 1; return x;'
 try 10 'if (1 < 2) {return 10;} else {return 20;}'
 try 20 'x = 0; i = 0; while (i < 10) {x = x + 2; i = i + 1;} return x;'
 try 10 'x = 0; for (i = 0; i < 10; i = i + 1) {x = x + 1;} return x;'
+try 6 $'#define MUL(a, b) ((a) * (b))\nreturn MUL(1 + 1, 3);'
+try 3 $'#define X 1\n#if X == 1 && defined(X)\nreturn 3;\n#else\nreturn 4;\n#endif'
+try 7 '_Static_assert(2 * 3 == 6, "six"); return 7;'
+try 5 $'// comment\nreturn /* block */ 5;'
+try 12 'x = 0; for (; x < 5;) x = x + 1; for (i = 0; ; i = i + 1) if (i == 7) return x + i; return 0;'
+try 5 'x = 5; for (i = 0; i < 3; i = i + 1) {}'
+try 0 'for (i = 0; i < 3; i = i + 1) {}'
+
+# ここから先はx86-64のみ
+if [ "$target" != x86_64 ]; then
+    echo OK
+    exit 0
+fi
+
+try 3 'asm("  mov rax, 3\n  mov rsp, rbp\n  pop rbp\n  ret");'
+try 3 'asm("  mov rax, 3 # 100%1 %%\n  mov rsp, rbp\n  pop rbp\n  ret");'
+try 9 'x = 4; asm("  mov %0, %1\n  add %0, %2" : "=r"(y) : "r"(x), "i"(5)); return y;'
+try 8 'x = 3; asm volatile ("  add %0, 5" : "+m"(x) : : "memory"); return x;'
+
+try_obj 47 '5+6*7;'
+try_obj 4 '(3+5)/2;'
+try_obj 1 '1+2+3 == 2*3;'
+try_obj 0 ' 1 >= 2;'
+try_obj 14 'if (1 > 2) return 7;else return 14; return 0;'
+try_obj 10 'x = 0; for (i = 0; i < 10; i = i + 1) {x = x + 1;} return x;'
+try_obj 20 'x = 0; i = 0; while (i < 10) {x = x + 2; i = i + 1;} return x;'
+
+try_att 47 '5+6*7;'
+try_att 4 '(3+5)/2;'
+try_att 0 ' 1 >= 2;'
+try_att 14 'if (1 > 2) return 7;else return 14; return 0;'
+try_att 10 'x = 0; for (i = 0; i < 10; i = i + 1) {x = x + 1;} return x;'
+try_att 30 'y = 3000000000; return y / 100000000;'
+try_att 9 'x = 4; asm("  movq %1, %0\n  addq %2, %0" : "=r"(y) : "r"(x), "i"(5)); return y;'
+try_att 8 'x = 3; asm volatile ("  addq $5, %0" : "+m"(x) : : "memory"); return x;'
+
+try_c 4 '(3+5)/2;'
+try_c 7 'a = b = 1 + 2 * 3; return a;'
+try_c 14 'if (1 > 2) return 7;else return 14; return 0;'
+try_c 10 'x = 0; for (i = 0; i < 10; i = i + 1) {x = x + 1;} return x;'
+try_c 2 'if (1) { if (0) return 1; } else return 3; return 2;'
+
+if command -v llc > /dev/null; then
+    try_llvm 47 '5+6*7;'
+    try_llvm 4 '(3+5)/2;'
+    try_llvm 1 '1+2+3 == 2*3;'
+    try_llvm 0 ' 1 >= 2;'
+    try_llvm 14 'if (1 > 2) return 7;else return 14; return 0;'
+    try_llvm 10 'x = 0; for (i = 0; i < 10; i = i + 1) {x = x + 1;} return x;'
+    try_llvm 20 'x = 0; i = 0; while (i < 10) {x = x + 2; i = i + 1;} return x;'
+    try_llvm 3 'a = b = 3; return a;'
+    try_llvm 5 'x = 5; for (i = 0; i < 3; i = i + 1) {}'
+fi
+
+try_static 47 '5+6*7;'
+try_static 14 'if (1 > 2) return 7;else return 14; return 0;'
+try_static 10 'x = 0; for (i = 0; i < 10; i = i + 1) {x = x + 1;} return x;'
+
+try_keep_output '1 +;'
+try_keep_output 'asm("nop");' -c
 
 echo OK
\ No newline at end of file